@@ -0,0 +1,80 @@
+#!/bin/sh
+# Times every benchmark script on each build of the VM, and on the script
+# compiled to C when a C compiler is installed. Reports peak memory too when
+# GNU time or Python is installed.
+# Usage: benches/compare.sh [runs]
+
+cd "$(dirname "$0")/.." || exit 1
+runs=${1:-5}
+out=target/bench
+mkdir -p "$out"
+
+variants="stack register-vm nan-boxing register-vm,nan-boxing jit"
+for variant in $variants; do
+    if [ "$variant" = stack ]; then
+        cargo build -q --release || exit 1
+    else
+        cargo build -q --release --features "$variant" || exit 1
+    fi
+    cp target/release/fusion "$out/fusion-$variant"
+done
+if command -v cc > /dev/null; then
+    variants="$variants aot"
+fi
+
+# Scripts may end in an error, like `deep.fu` overflowing the stack, so
+# only their output is compared and exit codes are ignored.
+best() {
+    best=
+    for _ in $(seq "$runs"); do
+        start=$(date +%s%N)
+        "$@" > /dev/null 2>&1
+        time=$(( ($(date +%s%N) - start) / 1000000 ))
+        if [ -z "$best" ] || [ "$time" -lt "$best" ]; then
+            best=$time
+        fi
+    done
+    echo "${best}ms"
+}
+
+peak_memory() {
+    if [ -x /usr/bin/time ]; then
+        /usr/bin/time -f '%MkB' "$@" 2>&1 > /dev/null | tail -1
+    elif command -v python3 > /dev/null; then
+        python3 -c '
+import resource, subprocess, sys
+subprocess.run(sys.argv[1:], stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+print("%dkB" % resource.getrusage(resource.RUSAGE_CHILDREN).ru_maxrss)
+' "$@"
+    else
+        echo -
+    fi
+}
+
+printf '%-8s' script
+for variant in $variants; do
+    printf ' %24s' "$variant"
+done
+echo
+
+for script in benches/*.fu; do
+    expected=$("$out/fusion-stack" "$script" 2>&1)
+    name=$(basename "$script" .fu)
+    printf '%-8s' "$name"
+    for variant in $variants; do
+        if [ "$variant" = aot ]; then
+            bin="$out/aot-$name"
+            "$out/fusion-stack" build "$script" -o "$bin.c" || exit 1
+            cc -O2 -o "$bin" "$bin.c" -lm || exit 1
+            set -- "$bin"
+        else
+            set -- "$out/fusion-$variant" "$script"
+        fi
+        if [ "$("$@" 2>&1)" != "$expected" ]; then
+            echo " $variant: output differs" >&2
+            exit 1
+        fi
+        printf ' %24s' "$(best "$@") $(peak_memory "$@")"
+    done
+    echo
+done
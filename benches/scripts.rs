@@ -0,0 +1,39 @@
+//! Times the fusion binary on every script in this directory.
+//! Run with `cargo bench`, plus `--features` to time another VM.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn scripts(c: &mut Criterion) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    let mut scripts: Vec<_> = fs::read_dir(dir)
+        .expect("can't read benches/")
+        .map(|entry| entry.expect("can't read benches/").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "fu"))
+        .collect();
+    scripts.sort();
+
+    let mut group = c.benchmark_group("scripts");
+    group.sample_size(10);
+    for script in scripts {
+        let name = script.file_stem().unwrap().to_string_lossy().into_owned();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                // Some scripts end in a runtime error on purpose, so only
+                // whether the binary ran at all is checked.
+                Command::new(env!("CARGO_BIN_EXE_fusion"))
+                    .arg(&script)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .expect("can't run fusion")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scripts);
+criterion_main!(benches);
@@ -0,0 +1,345 @@
+use crate::compiler::error::{BytecodeError, BytecodeResult};
+use crate::compiler::object::Function;
+use crate::compiler::value::Value;
+use crate::compiler::verifier::stack_depths;
+use crate::vm::opcode::Opcode;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Values, arithmetic, the collector and `puts`, in C.
+const RUNTIME: &str = include_str!("runtime.c");
+
+/// Translates `script` and the functions nested in it to a C program, which
+/// only needs the C standard library and `-lm` to build. Each function
+/// becomes a C function whose stack slots live at fixed offsets of its
+/// frame, so instructions turn into assignments between slots.
+pub fn emit_c(script: &Function) -> BytecodeResult<String> {
+    let mut functions = vec![];
+    collect_functions(script, &mut functions);
+
+    let mut emitter = Emitter {
+        ids: functions
+            .iter()
+            .enumerate()
+            .map(|(id, fun)| (*fun as *const Function, id))
+            .collect(),
+        global_names: script.global_names().to_vec(),
+        out: String::new(),
+    };
+
+    emitter.line("/* Compiled by `fusion build`. */");
+    emitter.out.push_str(RUNTIME);
+    emitter.line("");
+    for id in 0..functions.len() {
+        emitter.line(&format!("static Value fn_{}(Value *fp);", id));
+    }
+    for (id, fun) in functions.iter().enumerate() {
+        emitter.line(&format!(
+            "static const Function fun_{} = {{ {}, {}, fn_{} }};",
+            id,
+            c_string(fun.name().as_bytes()),
+            fun.arity(),
+            id
+        ));
+    }
+    for (id, fun) in functions.iter().enumerate() {
+        emitter.constants(id, fun);
+    }
+    for (id, fun) in functions.iter().enumerate() {
+        emitter.function(id, fun)?;
+    }
+    emitter.main();
+    Ok(emitter.out)
+}
+
+fn collect_functions<'a>(fun: &'a Function, functions: &mut Vec<&'a Function>) {
+    functions.push(fun);
+    for constant in fun.chunk().constants() {
+        if let Value::Function(nested) = constant {
+            collect_functions(nested, functions);
+        }
+    }
+}
+
+/// Whether any instruction of `fun` reads its constant table. Nested
+/// functions are reached through `Closure`, which names them directly.
+fn loads_constants(fun: &Function) -> bool {
+    let code = fun.chunk().code();
+    let mut offset = 0;
+    while let Some(opcode) = code.get(offset).and_then(|&byte| Opcode::from_byte(byte)) {
+        if matches!(
+            opcode,
+            Opcode::Constant | Opcode::ConstantLong | Opcode::AddConstant
+        ) {
+            return true;
+        }
+        offset += 1 + opcode.operand_len();
+    }
+    false
+}
+
+/// Where the jump at `offset` lands. Its operand counts from the end of the
+/// instruction.
+fn jump_target(offset: usize, opcode: Opcode, operand: usize) -> usize {
+    offset + 1 + opcode.operand_len() + operand
+}
+
+struct Emitter {
+    /// Each function's number, by address.
+    ids: HashMap<*const Function, usize>,
+    /// The script's globals, by slot.
+    global_names: Vec<String>,
+    out: String,
+}
+
+impl Emitter {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Emits the constants of function `id` as `k_<id>`.
+    fn constants(&mut self, id: usize, fun: &Function) {
+        let constants = fun.chunk().constants();
+        if !loads_constants(fun) {
+            return;
+        }
+
+        let mut values = vec![];
+        for (index, constant) in constants.iter().enumerate() {
+            values.push(match constant {
+                Value::Int(n) => format!("{{ T_INT, {{ .i = {} }} }}", c_int(*n)),
+                Value::BigInt(n) => {
+                    let digits: Vec<String> =
+                        n.digits().iter().map(|d| format!("{:#x}u", d)).collect();
+                    self.line(&format!(
+                        "static Big big_{}_{} = {{ {{ NULL, false, 0 }}, {}, {}, (const uint32_t[]){{ {} }} }};",
+                        id,
+                        index,
+                        n.is_negative(),
+                        digits.len(),
+                        digits.join(", ")
+                    ));
+                    format!("{{ T_BIG, {{ .big = &big_{}_{} }} }}", id, index)
+                }
+                Value::Float(n) => format!("{{ T_FLOAT, {{ .f = {} }} }}", c_float(*n)),
+                Value::Bool(b) => format!("{{ T_BOOL, {{ .b = {} }} }}", b),
+                Value::String(s) => {
+                    format!("{{ T_STRING, {{ .s = {} }} }}", c_string(s.as_str().as_bytes()))
+                }
+                Value::Function(nested) => {
+                    let nested: &Function = nested;
+                    format!("{{ T_FUNCTION, {{ .fun = &fun_{} }} }}", self.ids[&(nested as *const Function)])
+                }
+                // Closures only exist at run time.
+                Value::Closure(_) => "{ T_UNDEFINED, { .i = 0 } }".to_string(),
+            });
+        }
+        self.line(&format!("static Value k_{}[] = {{", id));
+        for value in values {
+            self.line(&format!("    {},", value));
+        }
+        self.line("};");
+    }
+
+    fn function(&mut self, id: usize, fun: &Function) -> BytecodeResult<()> {
+        let depths = stack_depths(fun, self.global_names.len())
+            .map_err(|error| BytecodeError::Verify(fun.name().to_string(), error))?;
+        let code = fun.chunk().code();
+        let instructions: Vec<(usize, usize, Opcode, usize)> = depths
+            .iter()
+            .enumerate()
+            .filter_map(|(offset, depth)| {
+                let depth = (*depth)?;
+                let opcode = Opcode::from_byte(code[offset])?;
+                let next = offset + 1 + opcode.operand_len();
+                let operand = code[offset + 1..next]
+                    .iter()
+                    .fold(0, |operand, &byte| operand << 8 | byte as usize);
+                Some((offset, depth, opcode, operand))
+            })
+            .collect();
+
+        let targets: HashSet<usize> = instructions
+            .iter()
+            .filter(|(_, _, opcode, _)| matches!(opcode, Opcode::Jump | Opcode::JumpIfFalse))
+            .map(|&(offset, _, opcode, operand)| jump_target(offset, opcode, operand))
+            .collect();
+        let slots = instructions
+            .iter()
+            .map(|&(_, depth, _, _)| depth + 1)
+            .max()
+            .unwrap_or(0);
+
+        let name = match fun.name() {
+            "" => "<script>",
+            name => name,
+        };
+        self.line("");
+        self.line(&format!("/* {} */", name.replace("*/", "* /")));
+        self.line(&format!("static Value fn_{}(Value *fp) {{", id));
+        self.line(&format!(
+            "    rt_enter(fp, {}, {});",
+            *fun.arity() as usize + 1,
+            slots
+        ));
+
+        for (offset, depth, opcode, operand) in instructions {
+            if targets.contains(&offset) {
+                self.line(&format!("L{}:;", offset));
+            }
+
+            let top = depth.wrapping_sub(1);
+            let statement = match opcode {
+                Opcode::Constant | Opcode::ConstantLong => {
+                    format!("fp[{}] = k_{}[{}];", depth, id, operand)
+                }
+                Opcode::AddConstant => {
+                    format!("fp[{0}] = rt_add(fp[{0}], k_{1}[{2}]);", top, id, operand)
+                }
+                Opcode::Add
+                | Opcode::Subtract
+                | Opcode::Multiply
+                | Opcode::Divide
+                | Opcode::Modulo
+                | Opcode::Power
+                | Opcode::BitAnd
+                | Opcode::BitOr
+                | Opcode::BitXor
+                | Opcode::ShiftLeft
+                | Opcode::ShiftRight => format!(
+                    "fp[{0}] = {1}(fp[{0}], fp[{2}]);",
+                    depth - 2,
+                    binary_function(opcode),
+                    top
+                ),
+                Opcode::Negate => format!("fp[{0}] = rt_negate(fp[{0}]);", top),
+                Opcode::BitNot => format!("fp[{0}] = rt_bit_not(fp[{0}]);", top),
+                Opcode::GetLocal | Opcode::GetLocalLong => {
+                    format!("fp[{}] = fp[{}];", depth, operand)
+                }
+                Opcode::SetLocal | Opcode::SetLocalLong | Opcode::SetLocalPop => {
+                    format!("fp[{}] = fp[{}];", operand, top)
+                }
+                Opcode::GetGlobalSlot => format!("fp[{}] = rt_get_global({});", depth, operand),
+                Opcode::SetGlobalSlot => format!("globals[{}] = fp[{}];", operand, top),
+                Opcode::Puts => format!("rt_puts(fp[{}]);", top),
+                Opcode::Closure | Opcode::ClosureLong => match &fun.chunk().constants()[operand] {
+                    Value::Function(nested) => {
+                        let nested: &Function = nested;
+                        format!(
+                            "fp[{}] = rt_closure(&fun_{});",
+                            depth,
+                            self.ids[&(nested as *const Function)]
+                        )
+                    }
+                    _ => "rt_fail(\"operands have the wrong types\");".to_string(),
+                },
+                Opcode::Call => format!(
+                    "fp[{0}] = rt_call(fp + {0}, {1});",
+                    depth - operand - 1,
+                    operand
+                ),
+                Opcode::TailCall => format!(
+                    "return rt_tail_call(fp, fp + {}, {});",
+                    depth - operand - 1,
+                    operand
+                ),
+                Opcode::Pop => continue,
+                Opcode::Jump => format!("goto L{};", jump_target(offset, opcode, operand)),
+                Opcode::JumpIfFalse => format!(
+                    "if (rt_is_falsy(fp[{}])) goto L{};",
+                    top,
+                    jump_target(offset, opcode, operand)
+                ),
+                Opcode::Return => format!("return fp[{}];", top),
+            };
+            self.line(&format!("    {}", statement));
+        }
+        self.line("}");
+        Ok(())
+    }
+
+    fn main(&mut self) {
+        let names: Vec<String> = self
+            .global_names
+            .iter()
+            .map(|name| c_string(name.as_bytes()))
+            .chain(Some("NULL".to_string()))
+            .collect();
+
+        self.line("");
+        self.line(&format!(
+            "static const char *const names[] = {{ {} }};",
+            names.join(", ")
+        ));
+        self.line("");
+        self.line("int main(void) {");
+        self.line(&format!(
+            "    return rt_run(&fun_0, names, {});",
+            self.global_names.len()
+        ));
+        self.line("}");
+    }
+}
+
+fn binary_function(opcode: Opcode) -> &'static str {
+    match opcode {
+        Opcode::Add => "rt_add",
+        Opcode::Subtract => "rt_sub",
+        Opcode::Multiply => "rt_mul",
+        Opcode::Divide => "rt_div",
+        Opcode::Modulo => "rt_mod",
+        Opcode::Power => "rt_pow",
+        Opcode::BitAnd => "rt_bit_and",
+        Opcode::BitOr => "rt_bit_or",
+        Opcode::BitXor => "rt_bit_xor",
+        Opcode::ShiftLeft => "rt_shift_left",
+        Opcode::ShiftRight => "rt_shift_right",
+        _ => unreachable!("{:?} isn't a binary operator", opcode),
+    }
+}
+
+/// A C literal for `n`, whose most negative value has no literal of its own.
+fn c_int(n: i64) -> String {
+    if n == i64::MIN {
+        "INT64_MIN".to_string()
+    } else {
+        format!("INT64_C({})", n)
+    }
+}
+
+/// A C literal for `n`, in hexadecimal so it's exact.
+fn c_float(n: f64) -> String {
+    if n.is_nan() {
+        return "NAN".to_string();
+    } else if n.is_infinite() {
+        return if n > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string();
+    }
+
+    let bits = n.to_bits();
+    let sign = if bits >> 63 == 1 { "-" } else { "" };
+    let exponent = (bits >> 52 & 0x7ff) as i64;
+    let mantissa = bits & ((1 << 52) - 1);
+    if exponent == 0 {
+        format!("{}0x0.{:013x}p-1022", sign, mantissa)
+    } else {
+        format!("{}0x1.{:013x}p{}", sign, mantissa, exponent - 1023)
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            b' '..=b'~' if byte != b'?' => literal.push(byte as char),
+            _ => write!(literal, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
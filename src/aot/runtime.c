@@ -0,0 +1,1132 @@
+/*
+ * The fusion runtime, copied into every script compiled by `fusion build`.
+ *
+ * It mirrors the interpreter: ints overflow into big integers, errors are
+ * reported the same way, and calls are limited to the same depth. Compiled
+ * functions keep their slots on one value stack, which the mark and sweep
+ * collector scans along with the globals.
+ */
+
+#include <inttypes.h>
+#include <math.h>
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* A script only calls the parts of the runtime it needs. */
+#if defined(__GNUC__)
+#pragma GCC diagnostic ignored "-Wunused-function"
+#endif
+
+#define MAX_FRAMES 10000
+#define MAX_STACK (MAX_FRAMES * 256)
+#define MAX_TRACE_CYCLE 4
+#define MIN_GC_BYTES (1 << 20)
+
+typedef enum {
+    T_INT,
+    T_BIG,
+    T_FLOAT,
+    T_BOOL,
+    T_STRING,
+    T_CLOSURE,
+    T_FUNCTION,
+    T_UNDEFINED
+} Tag;
+
+/* The header of everything the collector manages. */
+typedef struct Obj {
+    struct Obj *next;
+    bool marked;
+    size_t size;
+} Obj;
+
+/* Little-endian base 2^32 digits without trailing zeros, like BigInt. */
+typedef struct Big {
+    Obj obj;
+    bool negative;
+    size_t len;
+    const uint32_t *digits;
+} Big;
+
+struct Value;
+
+typedef struct Function {
+    const char *name;
+    int arity;
+    struct Value (*code)(struct Value *fp);
+} Function;
+
+typedef struct Closure {
+    Obj obj;
+    const Function *fun;
+} Closure;
+
+typedef struct Value {
+    Tag tag;
+    union {
+        int64_t i;
+        Big *big;
+        double f;
+        bool b;
+        const char *s;
+        Closure *closure;
+        const Function *fun;
+    } as;
+} Value;
+
+static Value *stack;
+static Value *stack_end;
+/* Every slot below this holds a live or cleared value. */
+static Value *stack_top;
+
+static Value *globals;
+static const char *const *global_names;
+static size_t global_count;
+
+static const char *frames[MAX_FRAMES];
+static size_t frame_count;
+/* Set by a tail call for `rt_call` to make, with this many arguments. */
+static bool tail_pending;
+static int tail_argc;
+
+static Obj *objects;
+static size_t allocated;
+static size_t next_gc = MIN_GC_BYTES;
+
+static _Noreturn void rt_fail(const char *format, ...) {
+    va_list args;
+    fflush(stdout);
+    fputs("error: ", stderr);
+    va_start(args, format);
+    vfprintf(stderr, format, args);
+    va_end(args);
+    fputs("\n", stderr);
+    exit(1);
+}
+
+/* Prints n with its digits grouped in threes, like `FrameSummary`. */
+static void print_grouped(size_t n) {
+    if (n < 1000) {
+        fprintf(stderr, "%zu", n);
+        return;
+    }
+    print_grouped(n / 1000);
+    fprintf(stderr, ",%03zu", n % 1000);
+}
+
+/* Folds recursion in the trace together, like `VM::stack_trace`. */
+static _Noreturn void rt_stack_overflow(void) {
+    size_t n = frame_count, i = 0;
+
+    fflush(stdout);
+    fputs("error: stack overflow", stderr);
+    while (i < n) {
+        size_t best_len = 1, best_calls = 1, best_score = 0;
+        for (size_t len = 1; len <= MAX_TRACE_CYCLE; len++) {
+            size_t cycle = i + len <= n ? len : n - i;
+            size_t calls = 0;
+            for (size_t at = i; at < n; at += len) {
+                size_t chunk = at + len <= n ? len : n - at;
+                bool same = chunk == cycle;
+                for (size_t k = 0; same && k < chunk; k++) {
+                    same = strcmp(frames[n - 1 - (at + k)], frames[n - 1 - (i + k)]) == 0;
+                }
+                if (!same) {
+                    break;
+                }
+                calls++;
+            }
+            size_t score = calls > 1 ? len * calls : 0;
+            if (calls > 1 && score > best_score) {
+                best_len = len;
+                best_calls = calls;
+                best_score = score;
+            }
+        }
+
+        fputs("\n    in ", stderr);
+        for (size_t k = 0; k < best_len; k++) {
+            fprintf(stderr, "%s%s", k ? " -> " : "", frames[n - 1 - (i + k)]);
+        }
+        if (best_calls > 1) {
+            fputs(" called ", stderr);
+            print_grouped(best_calls);
+            fputs(" times", stderr);
+        }
+        i += best_len * best_calls;
+    }
+    fputs("\n", stderr);
+    exit(1);
+}
+
+static void *rt_alloc(size_t size) {
+    Obj *obj = malloc(size);
+    if (obj == NULL) {
+        rt_fail("out of memory");
+    }
+    obj->next = objects;
+    obj->marked = false;
+    obj->size = size;
+    objects = obj;
+    allocated += size;
+    return obj;
+}
+
+static void mark_value(Value value) {
+    if (value.tag == T_BIG) {
+        value.as.big->obj.marked = true;
+    } else if (value.tag == T_CLOSURE) {
+        value.as.closure->obj.marked = true;
+    }
+}
+
+/* Objects don't refer to each other, so marking the roots is enough. */
+static void rt_collect(void) {
+    for (Value *slot = stack; slot < stack_top; slot++) {
+        mark_value(*slot);
+    }
+    for (size_t i = 0; i < global_count; i++) {
+        mark_value(globals[i]);
+    }
+
+    Obj **link = &objects;
+    allocated = 0;
+    while (*link != NULL) {
+        Obj *obj = *link;
+        if (obj->marked) {
+            obj->marked = false;
+            allocated += obj->size;
+            link = &obj->next;
+        } else {
+            *link = obj->next;
+            free(obj);
+        }
+    }
+    next_gc = allocated * 2 > MIN_GC_BYTES ? allocated * 2 : MIN_GC_BYTES;
+}
+
+static Value rt_int(int64_t n) {
+    Value value;
+    value.tag = T_INT;
+    value.as.i = n;
+    return value;
+}
+
+static Value rt_float(double n) {
+    Value value;
+    value.tag = T_FLOAT;
+    value.as.f = n;
+    return value;
+}
+
+static bool is_integer(Value value) {
+    return value.tag == T_INT || value.tag == T_BIG;
+}
+
+/* `n >> bits` rounding towards negative infinity, for any sign of `n`. */
+static int64_t shift_right_i64(int64_t n, unsigned bits) {
+    return n < 0 ? ~(~n >> bits) : n >> bits;
+}
+
+/* Big integer arithmetic, on temporaries that own their digits. */
+
+typedef struct Num {
+    bool negative;
+    size_t len;
+    uint32_t *digits;
+} Num;
+
+static Num num_new(bool negative, size_t len) {
+    Num n;
+    n.negative = negative;
+    n.len = len;
+    n.digits = calloc(len ? len : 1, sizeof(uint32_t));
+    if (n.digits == NULL) {
+        rt_fail("out of memory");
+    }
+    return n;
+}
+
+static void num_free(Num *n) {
+    free(n->digits);
+}
+
+static void num_trim(Num *n) {
+    while (n->len > 0 && n->digits[n->len - 1] == 0) {
+        n->len--;
+    }
+    if (n->len == 0) {
+        n->negative = false;
+    }
+}
+
+static Num num_from_i64(int64_t value) {
+    uint64_t magnitude = value < 0 ? -(uint64_t)value : (uint64_t)value;
+    Num n = num_new(value < 0, 2);
+    n.digits[0] = (uint32_t)magnitude;
+    n.digits[1] = (uint32_t)(magnitude >> 32);
+    num_trim(&n);
+    return n;
+}
+
+static Num num_from_value(Value value) {
+    if (value.tag == T_INT) {
+        return num_from_i64(value.as.i);
+    }
+    Num n = num_new(value.as.big->negative, value.as.big->len);
+    memcpy(n.digits, value.as.big->digits, n.len * sizeof(uint32_t));
+    return n;
+}
+
+static Num num_copy(const Num *n) {
+    Num copy = num_new(n->negative, n->len);
+    memcpy(copy.digits, n->digits, n->len * sizeof(uint32_t));
+    return copy;
+}
+
+static bool num_to_i64(const Num *n, int64_t *out) {
+    if (n->len > 2) {
+        return false;
+    }
+    uint64_t magnitude = 0;
+    for (size_t i = n->len; i-- > 0;) {
+        magnitude = magnitude << 32 | n->digits[i];
+    }
+    if (n->negative) {
+        if (magnitude > (uint64_t)INT64_MAX + 1) {
+            return false;
+        }
+        *out = magnitude == (uint64_t)INT64_MAX + 1 ? INT64_MIN : -(int64_t)magnitude;
+    } else {
+        if (magnitude > (uint64_t)INT64_MAX) {
+            return false;
+        }
+        *out = (int64_t)magnitude;
+    }
+    return true;
+}
+
+static double big_to_f64(bool negative, size_t len, const uint32_t *digits) {
+    double magnitude = 0.0;
+    for (size_t i = len; i-- > 0;) {
+        magnitude = magnitude * 4294967296.0 + digits[i];
+    }
+    return negative ? -magnitude : magnitude;
+}
+
+/* Stores an integer result as an int when it fits in 64 bits, freeing `n`. */
+static Value rt_from_num(Num n) {
+    int64_t small;
+    if (num_to_i64(&n, &small)) {
+        num_free(&n);
+        return rt_int(small);
+    }
+
+    Big *big = rt_alloc(sizeof(Big) + n.len * sizeof(uint32_t));
+    uint32_t *digits = (uint32_t *)(big + 1);
+    memcpy(digits, n.digits, n.len * sizeof(uint32_t));
+    big->negative = n.negative;
+    big->len = n.len;
+    big->digits = digits;
+    num_free(&n);
+
+    Value value;
+    value.tag = T_BIG;
+    value.as.big = big;
+    return value;
+}
+
+static int cmp_magnitude(const Num *a, const Num *b) {
+    if (a->len != b->len) {
+        return a->len < b->len ? -1 : 1;
+    }
+    for (size_t i = a->len; i-- > 0;) {
+        if (a->digits[i] != b->digits[i]) {
+            return a->digits[i] < b->digits[i] ? -1 : 1;
+        }
+    }
+    return 0;
+}
+
+static Num add_magnitude(const Num *a, const Num *b) {
+    const Num *longer = a->len >= b->len ? a : b;
+    const Num *shorter = a->len >= b->len ? b : a;
+    Num result = num_new(false, longer->len + 1);
+    uint64_t carry = 0;
+    for (size_t i = 0; i < longer->len; i++) {
+        uint64_t sum = (uint64_t)longer->digits[i] + (i < shorter->len ? shorter->digits[i] : 0) + carry;
+        result.digits[i] = (uint32_t)sum;
+        carry = sum >> 32;
+    }
+    result.digits[longer->len] = (uint32_t)carry;
+    num_trim(&result);
+    return result;
+}
+
+/* Computes `a - b`, requiring `|a| >= |b|`. */
+static Num sub_magnitude(const Num *a, const Num *b) {
+    Num result = num_new(false, a->len);
+    int64_t borrow = 0;
+    for (size_t i = 0; i < a->len; i++) {
+        int64_t diff = (int64_t)a->digits[i] - (i < b->len ? b->digits[i] : 0) - borrow;
+        borrow = 0;
+        if (diff < 0) {
+            diff += (int64_t)1 << 32;
+            borrow = 1;
+        }
+        result.digits[i] = (uint32_t)diff;
+    }
+    num_trim(&result);
+    return result;
+}
+
+static Num num_add(const Num *a, const Num *b) {
+    Num result;
+    if (a->negative == b->negative) {
+        result = add_magnitude(a, b);
+        result.negative = a->negative;
+    } else if (cmp_magnitude(a, b) < 0) {
+        result = sub_magnitude(b, a);
+        result.negative = b->negative;
+    } else {
+        result = sub_magnitude(a, b);
+        result.negative = a->negative;
+    }
+    num_trim(&result);
+    return result;
+}
+
+static Num num_sub(const Num *a, const Num *b) {
+    Num negated = *b;
+    negated.negative = !b->negative && b->len > 0;
+    return num_add(a, &negated);
+}
+
+static Num num_mul(const Num *a, const Num *b) {
+    Num result = num_new(a->negative != b->negative, a->len + b->len);
+    for (size_t i = 0; i < a->len; i++) {
+        uint64_t carry = 0;
+        for (size_t j = 0; j < b->len; j++) {
+            uint64_t t = (uint64_t)a->digits[i] * b->digits[j] + result.digits[i + j] + carry;
+            result.digits[i + j] = (uint32_t)t;
+            carry = t >> 32;
+        }
+        result.digits[i + b->len] = (uint32_t)carry;
+    }
+    num_trim(&result);
+    return result;
+}
+
+/* Divides `n` in place by `divisor`, returning the remainder. */
+static uint32_t div_rem_small(Num *n, uint32_t divisor) {
+    uint64_t remainder = 0;
+    for (size_t i = n->len; i-- > 0;) {
+        uint64_t t = remainder << 32 | n->digits[i];
+        n->digits[i] = (uint32_t)(t / divisor);
+        remainder = t % divisor;
+    }
+    num_trim(n);
+    return (uint32_t)remainder;
+}
+
+static Num shl_digits(const uint32_t *digits, size_t len, unsigned shift) {
+    Num result = num_new(false, len + 1);
+    uint32_t carry = 0;
+    for (size_t i = 0; i < len; i++) {
+        result.digits[i] = shift ? digits[i] << shift | carry : digits[i];
+        carry = shift ? digits[i] >> (32 - shift) : 0;
+    }
+    result.digits[len] = carry;
+    num_trim(&result);
+    return result;
+}
+
+static Num shr_digits(const uint32_t *digits, size_t len, unsigned shift) {
+    Num result = num_new(false, len);
+    for (size_t i = 0; i < len; i++) {
+        uint32_t high = shift && i + 1 < len ? digits[i + 1] << (32 - shift) : 0;
+        result.digits[i] = digits[i] >> shift | high;
+    }
+    num_trim(&result);
+    return result;
+}
+
+/* Truncating division of magnitudes (Knuth, TAOCP vol. 2, algorithm D). */
+static void div_rem_magnitude(const Num *u, const Num *v, Num *quotient, Num *remainder) {
+    if (cmp_magnitude(u, v) < 0) {
+        *quotient = num_new(false, 0);
+        *remainder = num_copy(u);
+        remainder->negative = false;
+        return;
+    }
+    if (v->len == 1) {
+        *quotient = num_copy(u);
+        quotient->negative = false;
+        *remainder = num_from_i64(div_rem_small(quotient, v->digits[0]));
+        return;
+    }
+
+    /* Normalize so the top digit of the divisor has its high bit set. */
+    unsigned shift = 0;
+    while ((v->digits[v->len - 1] << shift & 0x80000000u) == 0) {
+        shift++;
+    }
+    Num vn = shl_digits(v->digits, v->len, shift);
+    Num un = num_new(false, u->len + 1);
+    Num shifted = shl_digits(u->digits, u->len, shift);
+    memcpy(un.digits, shifted.digits, shifted.len * sizeof(uint32_t));
+    num_free(&shifted);
+
+    size_t n = vn.len, m = u->len - n;
+    Num q = num_new(false, m + 1);
+
+    for (size_t j = m + 1; j-- > 0;) {
+        uint64_t num = (uint64_t)un.digits[j + n] << 32 | un.digits[j + n - 1];
+        uint64_t qhat = num / vn.digits[n - 1];
+        uint64_t rhat = num % vn.digits[n - 1];
+        while (qhat >= (uint64_t)1 << 32 ||
+               qhat * vn.digits[n - 2] > (rhat << 32 | un.digits[j + n - 2])) {
+            qhat--;
+            rhat += vn.digits[n - 1];
+            if (rhat >= (uint64_t)1 << 32) {
+                break;
+            }
+        }
+
+        /* Multiply and subtract. */
+        int64_t k = 0, t;
+        for (size_t i = 0; i < n; i++) {
+            uint64_t p = qhat * vn.digits[i];
+            t = (int64_t)un.digits[i + j] - k - (int64_t)(p & 0xffffffffu);
+            un.digits[i + j] = (uint32_t)t;
+            k = (int64_t)(p >> 32) - shift_right_i64(t, 32);
+        }
+        t = (int64_t)un.digits[j + n] - k;
+        un.digits[j + n] = (uint32_t)t;
+
+        q.digits[j] = (uint32_t)qhat;
+        if (t < 0) {
+            /* Subtracted too much, add the divisor back. */
+            uint64_t carry = 0;
+            q.digits[j]--;
+            for (size_t i = 0; i < n; i++) {
+                uint64_t sum = (uint64_t)un.digits[i + j] + vn.digits[i] + carry;
+                un.digits[i + j] = (uint32_t)sum;
+                carry = sum >> 32;
+            }
+            un.digits[j + n] += (uint32_t)carry;
+        }
+    }
+
+    *remainder = shr_digits(un.digits, n, shift);
+    num_trim(&q);
+    *quotient = q;
+    num_free(&vn);
+    num_free(&un);
+}
+
+/* Floored division, where the remainder takes the sign of the divisor. */
+static bool num_div_mod_floor(const Num *a, const Num *b, Num *quotient, Num *remainder) {
+    if (b->len == 0) {
+        return false;
+    }
+
+    div_rem_magnitude(a, b, quotient, remainder);
+    quotient->negative = a->negative != b->negative;
+    remainder->negative = a->negative;
+    num_trim(quotient);
+    num_trim(remainder);
+
+    if (remainder->len > 0 && remainder->negative != b->negative) {
+        Num one = num_from_i64(1);
+        Num q = num_sub(quotient, &one);
+        Num r = num_add(remainder, b);
+        num_free(&one);
+        num_free(quotient);
+        num_free(remainder);
+        *quotient = q;
+        *remainder = r;
+    }
+    return true;
+}
+
+static Num num_shift_left(const Num *n, uint32_t bits) {
+    size_t limbs = bits / 32;
+    Num shifted = shl_digits(n->digits, n->len, bits % 32);
+    Num result = num_new(n->negative, limbs + shifted.len);
+    memcpy(result.digits + limbs, shifted.digits, shifted.len * sizeof(uint32_t));
+    num_free(&shifted);
+    num_trim(&result);
+    return result;
+}
+
+/* Arithmetic shift, rounding towards negative infinity. */
+static Num num_shift_right(const Num *n, uint32_t bits) {
+    if (n->negative) {
+        /* -x >> n == -((x - 1) >> n) - 1 */
+        Num one = num_from_i64(1);
+        Num x = num_copy(n);
+        x.negative = false;
+        Num smaller = num_sub(&x, &one);
+        Num shifted = num_shift_right(&smaller, bits);
+        shifted.negative = shifted.len > 0;
+        Num result = num_sub(&shifted, &one);
+        num_free(&one);
+        num_free(&x);
+        num_free(&smaller);
+        num_free(&shifted);
+        return result;
+    }
+
+    size_t limbs = bits / 32;
+    if (limbs >= n->len) {
+        return num_new(false, 0);
+    }
+    return shr_digits(n->digits + limbs, n->len - limbs, bits % 32);
+}
+
+static void negate_twos_complement(uint32_t *digits, size_t len) {
+    uint64_t carry = 1;
+    for (size_t i = 0; i < len; i++) {
+        uint64_t t = (uint64_t)(uint32_t)~digits[i] + carry;
+        digits[i] = (uint32_t)t;
+        carry = t >> 32;
+    }
+}
+
+/* Applies a bitwise operator on the two's complement representations. */
+static Num num_bitwise(const Num *a, const Num *b, char op) {
+    size_t len = (a->len > b->len ? a->len : b->len) + 1;
+    Num x = num_new(false, len), y = num_new(false, len), result = num_new(false, len);
+    memcpy(x.digits, a->digits, a->len * sizeof(uint32_t));
+    memcpy(y.digits, b->digits, b->len * sizeof(uint32_t));
+    if (a->negative) {
+        negate_twos_complement(x.digits, len);
+    }
+    if (b->negative) {
+        negate_twos_complement(y.digits, len);
+    }
+
+    for (size_t i = 0; i < len; i++) {
+        result.digits[i] = op == '&' ? x.digits[i] & y.digits[i]
+                         : op == '|' ? x.digits[i] | y.digits[i]
+                                     : x.digits[i] ^ y.digits[i];
+    }
+    if (result.digits[len - 1] >> 31) {
+        negate_twos_complement(result.digits, len);
+        result.negative = true;
+    }
+    num_free(&x);
+    num_free(&y);
+    num_trim(&result);
+    return result;
+}
+
+static Num num_pow(const Num *n, uint32_t exponent) {
+    Num result = num_from_i64(1);
+    Num base = num_copy(n);
+    while (exponent > 0) {
+        if (exponent & 1) {
+            Num product = num_mul(&result, &base);
+            num_free(&result);
+            result = product;
+        }
+        exponent >>= 1;
+        if (exponent > 0) {
+            Num square = num_mul(&base, &base);
+            num_free(&base);
+            base = square;
+        }
+    }
+    num_free(&base);
+    return result;
+}
+
+static void print_big(const Big *big) {
+    Num magnitude;
+    magnitude.negative = false;
+    magnitude.len = big->len;
+    magnitude.digits = malloc((big->len ? big->len : 1) * sizeof(uint32_t));
+    uint32_t *chunks = malloc((big->len * 2 + 1) * sizeof(uint32_t));
+    if (magnitude.digits == NULL || chunks == NULL) {
+        rt_fail("out of memory");
+    }
+    memcpy(magnitude.digits, big->digits, big->len * sizeof(uint32_t));
+
+    size_t count = 0;
+    do {
+        chunks[count++] = div_rem_small(&magnitude, 1000000000);
+    } while (magnitude.len > 0);
+    if (big->negative) {
+        putchar('-');
+    }
+    printf("%" PRIu32, chunks[count - 1]);
+    while (count-- > 1) {
+        printf("%09" PRIu32, chunks[count - 1]);
+    }
+    free(magnitude.digits);
+    free(chunks);
+}
+
+/* Arithmetic on values. */
+
+static bool checked_add(int64_t a, int64_t b, int64_t *out) {
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) {
+        return false;
+    }
+    *out = a + b;
+    return true;
+}
+
+static bool checked_sub(int64_t a, int64_t b, int64_t *out) {
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b)) {
+        return false;
+    }
+    *out = a - b;
+    return true;
+}
+
+static bool checked_mul(int64_t a, int64_t b, int64_t *out) {
+    if (a > 0 ? (b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a)
+              : (b > 0 ? a < INT64_MIN / b : a != 0 && b < INT64_MAX / a)) {
+        return false;
+    }
+    *out = a * b;
+    return true;
+}
+
+static bool floor_div(int64_t a, int64_t b, int64_t *out) {
+    if (b == 0 || (a == INT64_MIN && b == -1)) {
+        return false;
+    }
+    *out = a / b - (a % b != 0 && (a < 0) != (b < 0));
+    return true;
+}
+
+static bool floor_mod(int64_t a, int64_t b, int64_t *out) {
+    if (b == 0 || (a == INT64_MIN && b == -1)) {
+        return false;
+    }
+    int64_t r = a % b;
+    *out = r != 0 && (r < 0) != (b < 0) ? r + b : r;
+    return true;
+}
+
+static bool big_add(const Num *a, const Num *b, Num *out) {
+    *out = num_add(a, b);
+    return true;
+}
+
+static bool big_sub(const Num *a, const Num *b, Num *out) {
+    *out = num_sub(a, b);
+    return true;
+}
+
+static bool big_mul(const Num *a, const Num *b, Num *out) {
+    *out = num_mul(a, b);
+    return true;
+}
+
+static bool big_div(const Num *a, const Num *b, Num *out) {
+    Num remainder;
+    if (!num_div_mod_floor(a, b, out, &remainder)) {
+        return false;
+    }
+    num_free(&remainder);
+    return true;
+}
+
+static bool big_mod(const Num *a, const Num *b, Num *out) {
+    Num quotient;
+    if (!num_div_mod_floor(a, b, &quotient, out)) {
+        return false;
+    }
+    num_free(&quotient);
+    return true;
+}
+
+static double float_add(double a, double b) { return a + b; }
+static double float_sub(double a, double b) { return a - b; }
+static double float_mul(double a, double b) { return a * b; }
+static double float_div(double a, double b) { return a / b; }
+
+static double float_mod(double a, double b) {
+    double r = fmod(a, b);
+    return r != 0.0 && (r < 0.0) != (b < 0.0) ? r + b : r;
+}
+
+static bool to_f64(Value value, double *out) {
+    switch (value.tag) {
+    case T_INT:
+        *out = (double)value.as.i;
+        return true;
+    case T_BIG:
+        *out = big_to_f64(value.as.big->negative, value.as.big->len, value.as.big->digits);
+        return true;
+    case T_FLOAT:
+        *out = value.as.f;
+        return true;
+    default:
+        return false;
+    }
+}
+
+/* Tries the int fast path, then big integers, then floats. */
+static Value arithmetic(Value a, Value b, bool (*int_op)(int64_t, int64_t, int64_t *),
+                        bool (*big_op)(const Num *, const Num *, Num *),
+                        double (*float_op)(double, double)) {
+    int64_t n;
+    if (a.tag == T_INT && b.tag == T_INT && int_op(a.as.i, b.as.i, &n)) {
+        return rt_int(n);
+    }
+
+    if (is_integer(a) && is_integer(b)) {
+        Num x = num_from_value(a), y = num_from_value(b), result;
+        bool ok = big_op(&x, &y, &result);
+        num_free(&x);
+        num_free(&y);
+        if (!ok) {
+            rt_fail("division by zero");
+        }
+        return rt_from_num(result);
+    }
+
+    double x, y;
+    if ((a.tag == T_FLOAT || b.tag == T_FLOAT) && to_f64(a, &x) && to_f64(b, &y)) {
+        return rt_float(float_op(x, y));
+    }
+    rt_fail("operands have the wrong types");
+}
+
+static Value rt_add(Value a, Value b) { return arithmetic(a, b, checked_add, big_add, float_add); }
+static Value rt_sub(Value a, Value b) { return arithmetic(a, b, checked_sub, big_sub, float_sub); }
+static Value rt_mul(Value a, Value b) { return arithmetic(a, b, checked_mul, big_mul, float_mul); }
+static Value rt_div(Value a, Value b) { return arithmetic(a, b, floor_div, big_div, float_div); }
+static Value rt_mod(Value a, Value b) { return arithmetic(a, b, floor_mod, big_mod, float_mod); }
+
+/* A power with an exponent too large to multiply out. Only bases 0, 1 and
+ * -1 have a result that fits anywhere. */
+static Value pow_huge(Value a, bool odd) {
+    if (a.tag == T_INT && (a.as.i == 0 || a.as.i == 1)) {
+        return a;
+    }
+    if (a.tag == T_INT && a.as.i == -1) {
+        return rt_int(odd ? -1 : 1);
+    }
+    rt_fail("integer overflow");
+}
+
+static Value rt_pow(Value a, Value b) {
+    if (is_integer(a) && b.tag == T_INT && b.as.i >= 0) {
+        if (b.as.i > UINT32_MAX) {
+            return pow_huge(a, b.as.i & 1);
+        }
+        uint32_t e = (uint32_t)b.as.i;
+        if (a.tag == T_INT) {
+            int64_t result = 1, base = a.as.i;
+            bool ok = true;
+            for (uint32_t left = e; ok && left > 0; left >>= 1) {
+                if (left & 1) {
+                    ok = checked_mul(result, base, &result);
+                }
+                if (ok && left > 1) {
+                    ok = checked_mul(base, base, &base);
+                }
+            }
+            if (ok) {
+                return rt_int(result);
+            }
+        }
+        Num x = num_from_value(a);
+        Num result = num_pow(&x, e);
+        num_free(&x);
+        return rt_from_num(result);
+    }
+    if (b.tag == T_BIG && !b.as.big->negative && is_integer(a)) {
+        return pow_huge(a, b.as.big->digits[0] & 1);
+    }
+
+    double x, y;
+    if (to_f64(a, &x) && to_f64(b, &y)) {
+        return rt_float(pow(x, y));
+    }
+    rt_fail("operands have the wrong types");
+}
+
+static Value bitwise(Value a, Value b, char op, const char *symbol) {
+    if (a.tag == T_INT && b.tag == T_INT) {
+        return rt_int(op == '&' ? a.as.i & b.as.i : op == '|' ? a.as.i | b.as.i : a.as.i ^ b.as.i);
+    }
+    if (is_integer(a) && is_integer(b)) {
+        Num x = num_from_value(a), y = num_from_value(b);
+        Num result = num_bitwise(&x, &y, op);
+        num_free(&x);
+        num_free(&y);
+        return rt_from_num(result);
+    }
+    rt_fail("'%s' expects integers", symbol);
+}
+
+static Value rt_bit_and(Value a, Value b) { return bitwise(a, b, '&', "&"); }
+static Value rt_bit_or(Value a, Value b) { return bitwise(a, b, '|', "|"); }
+static Value rt_bit_xor(Value a, Value b) { return bitwise(a, b, '^', "^"); }
+
+/* Shifts `a` by `b` bits, where a negative amount shifts the other way. */
+static Value shift(Value a, Value b, bool left) {
+    const char *symbol = left ? "<<" : ">>";
+    if (b.tag == T_BIG) {
+        rt_fail("integer overflow");
+    } else if (b.tag != T_INT) {
+        rt_fail("'%s' expects integers", symbol);
+    }
+
+    int64_t amount = b.as.i;
+    left = left == (amount >= 0);
+    uint64_t magnitude = amount < 0 ? -(uint64_t)amount : (uint64_t)amount;
+    if (magnitude > UINT32_MAX) {
+        rt_fail("integer overflow");
+    }
+    uint32_t bits = (uint32_t)magnitude;
+
+    if (a.tag == T_INT && !left) {
+        return rt_int(shift_right_i64(a.as.i, bits < 63 ? bits : 63));
+    }
+    if (a.tag == T_INT && bits < 64) {
+        int64_t shifted = (int64_t)((uint64_t)a.as.i << bits);
+        if (shift_right_i64(shifted, bits) == a.as.i) {
+            return rt_int(shifted);
+        }
+    }
+    if (is_integer(a)) {
+        Num n = num_from_value(a);
+        Num result = left ? num_shift_left(&n, bits) : num_shift_right(&n, bits);
+        num_free(&n);
+        return rt_from_num(result);
+    }
+    rt_fail("'%s' expects integers", symbol);
+}
+
+static Value rt_shift_left(Value a, Value b) { return shift(a, b, true); }
+static Value rt_shift_right(Value a, Value b) { return shift(a, b, false); }
+
+static Value rt_negate(Value a) {
+    if (a.tag == T_INT && a.as.i != INT64_MIN) {
+        return rt_int(-a.as.i);
+    } else if (is_integer(a)) {
+        Num n = num_from_value(a);
+        n.negative = !n.negative && n.len > 0;
+        return rt_from_num(n);
+    } else if (a.tag == T_FLOAT) {
+        return rt_float(-a.as.f);
+    }
+    rt_fail("operands have the wrong types");
+}
+
+/* Bitwise complement, `~x == -x - 1`. */
+static Value rt_bit_not(Value a) {
+    if (a.tag == T_INT) {
+        return rt_int(~a.as.i);
+    } else if (a.tag == T_BIG) {
+        Num n = num_from_value(a), one = num_from_i64(1);
+        n.negative = !n.negative && n.len > 0;
+        Num result = num_sub(&n, &one);
+        num_free(&n);
+        num_free(&one);
+        return rt_from_num(result);
+    }
+    rt_fail("'~' expects integers");
+}
+
+static bool rt_is_falsy(Value value) {
+    return value.tag == T_BOOL && !value.as.b;
+}
+
+/* Globals, calls and `puts`. */
+
+static Value rt_get_global(size_t slot) {
+    if (globals[slot].tag == T_UNDEFINED) {
+        rt_fail("undefined variable '%s'", global_names[slot]);
+    }
+    return globals[slot];
+}
+
+static Value rt_closure(const Function *fun) {
+    Closure *closure = rt_alloc(sizeof(Closure));
+    closure->fun = fun;
+
+    Value value;
+    value.tag = T_CLOSURE;
+    value.as.closure = closure;
+    return value;
+}
+
+/* Called by each function on entry, with its arguments and callee being
+ * the first `live` of its `slots`. */
+static void rt_enter(Value *fp, int live, int slots) {
+    if (fp + slots > stack_end) {
+        rt_stack_overflow();
+    }
+    /* Stale slots may point at collected objects. */
+    for (Value *slot = fp + live; slot < fp + slots; slot++) {
+        slot->tag = T_INT;
+    }
+    if (fp + slots > stack_top) {
+        stack_top = fp + slots;
+    }
+}
+
+/* Calls the callee at `base` with the `argc` arguments after it, running
+ * any tail calls it makes in its place. */
+static Value rt_call(Value *base, int argc) {
+    Value *top = stack_top;
+    for (;;) {
+        if (allocated > next_gc) {
+            rt_collect();
+        }
+        if (base[0].tag != T_CLOSURE) {
+            rt_fail("only functions can be called");
+        }
+        const Function *fun = base[0].as.closure->fun;
+        if (fun->arity != argc) {
+            rt_fail("wrong number of arguments");
+        }
+        if (frame_count >= MAX_FRAMES) {
+            rt_stack_overflow();
+        }
+
+        frames[frame_count++] = fun->name[0] ? fun->name : "<script>";
+        Value result = fun->code(base);
+        frame_count--;
+        if (!tail_pending) {
+            stack_top = top;
+            return result;
+        }
+        tail_pending = false;
+        argc = tail_argc;
+    }
+}
+
+/* Moves the callee at `callee` and its arguments down to `fp`, for the
+ * caller's `rt_call` to call once this function returns. */
+static Value rt_tail_call(Value *fp, Value *callee, int argc) {
+    memmove(fp, callee, (size_t)(argc + 1) * sizeof(Value));
+    tail_pending = true;
+    tail_argc = argc;
+    return fp[0];
+}
+
+/* Prints floats like Rust's `{:?}`: the shortest digits that read back as
+ * the same number, and an exponent for very large or small magnitudes. */
+static void print_float(double n) {
+    if (isnan(n)) {
+        fputs("NaN", stdout);
+        return;
+    } else if (isinf(n)) {
+        fputs(n < 0 ? "-inf" : "inf", stdout);
+        return;
+    } else if (n == 0.0) {
+        fputs(signbit(n) ? "-0.0" : "0.0", stdout);
+        return;
+    }
+
+    char buffer[40];
+    for (int precision = 0; precision <= 16; precision++) {
+        snprintf(buffer, sizeof buffer, "%.*e", precision, n);
+        if (strtod(buffer, NULL) == n) {
+            break;
+        }
+    }
+
+    char digits[20];
+    int count = 0;
+    const char *c = buffer;
+    if (*c == '-') {
+        putchar('-');
+        c++;
+    }
+    for (; *c != 'e'; c++) {
+        if (*c != '.') {
+            digits[count++] = *c;
+        }
+    }
+    int exponent = atoi(c + 1);
+
+    if (fabs(n) < 1e-4 || fabs(n) >= 1e16) {
+        putchar(digits[0]);
+        if (count > 1) {
+            putchar('.');
+            fwrite(digits + 1, 1, (size_t)count - 1, stdout);
+        }
+        printf("e%d", exponent);
+    } else if (exponent < 0) {
+        fputs("0.", stdout);
+        for (int i = 0; i < -exponent - 1; i++) {
+            putchar('0');
+        }
+        fwrite(digits, 1, (size_t)count, stdout);
+    } else {
+        for (int i = 0; i <= exponent; i++) {
+            putchar(i < count ? digits[i] : '0');
+        }
+        putchar('.');
+        if (count > exponent + 1) {
+            fwrite(digits + exponent + 1, 1, (size_t)(count - exponent - 1), stdout);
+        } else {
+            putchar('0');
+        }
+    }
+}
+
+static void rt_puts(Value value) {
+    switch (value.tag) {
+    case T_INT:
+        printf("%" PRId64, value.as.i);
+        break;
+    case T_BIG:
+        print_big(value.as.big);
+        break;
+    case T_FLOAT:
+        print_float(value.as.f);
+        break;
+    case T_BOOL:
+        fputs(value.as.b ? "true" : "false", stdout);
+        break;
+    case T_STRING:
+        fputs(value.as.s, stdout);
+        break;
+    case T_CLOSURE:
+        printf("Closure(Gc(%p))", (void *)value.as.closure);
+        break;
+    case T_FUNCTION:
+        printf("Function(<fn %s>)", value.as.fun->name);
+        break;
+    case T_UNDEFINED:
+        break;
+    }
+    putchar('\n');
+}
+
+/* Runs the script, given the names of its global slots. */
+static int rt_run(const Function *script, const char *const *names, size_t count) {
+    stack = calloc(MAX_STACK, sizeof(Value));
+    globals = calloc(count ? count : 1, sizeof(Value));
+    if (stack == NULL || globals == NULL) {
+        rt_fail("out of memory");
+    }
+    stack_end = stack + MAX_STACK;
+    global_names = names;
+    global_count = count;
+    for (size_t i = 0; i < count; i++) {
+        globals[i].tag = T_UNDEFINED;
+    }
+
+    stack[0] = rt_closure(script);
+    stack_top = stack + 1;
+    rt_call(stack, 0);
+    fflush(stdout);
+    return 0;
+}
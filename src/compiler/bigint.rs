@@ -0,0 +1,660 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter, Result};
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+const BASE: u64 = 1 << 32;
+const DECIMAL_CHUNK: u32 = 1_000_000_000;
+
+thread_local! {
+    /// Bytes held by the digits of every `BigInt` alive on this thread.
+    static LIVE_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+/// How many bytes of big integer digits are alive on this thread, so the VM
+/// can hold them against its heap limit.
+pub fn live_bytes() -> usize {
+    LIVE_BYTES.with(Cell::get)
+}
+
+/// Arbitrary-precision signed integer.
+///
+/// The magnitude is stored as little-endian base 2^32 digits without
+/// trailing zeros, so zero is an empty digit vector and is never negative.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct ParseBigIntError;
+
+impl BigInt {
+    pub fn zero() -> Self {
+        BigInt {
+            negative: false,
+            digits: vec![],
+        }
+    }
+
+    fn from_parts(negative: bool, mut digits: Vec<u32>) -> Self {
+        trim(&mut digits);
+        let negative = negative && !digits.is_empty();
+        LIVE_BYTES.with(|live| live.set(live.get() + digits.capacity() * 4));
+        BigInt { negative, digits }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_odd(&self) -> bool {
+        self.digits.first().is_some_and(|d| d & 1 == 1)
+    }
+
+    /// The magnitude's base 2^32 digits, least significant first.
+    pub fn digits(&self) -> &[u32] {
+        &self.digits
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &d| (acc << 32) | d as u64);
+        if self.negative {
+            if magnitude <= i64::MAX as u64 + 1 {
+                Some((magnitude as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else if magnitude <= i64::MAX as u64 {
+            Some(magnitude as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &d| acc * BASE as f64 + d as f64);
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    /// Parses an optionally signed string of digits in the given radix.
+    pub fn from_str_radix(s: &str, radix: u32) -> std::result::Result<Self, ParseBigIntError> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if digits.is_empty() {
+            return Err(ParseBigIntError);
+        }
+
+        let mut magnitude = vec![];
+        for c in digits.chars() {
+            let digit = c.to_digit(radix).ok_or(ParseBigIntError)?;
+            mul_add_small(&mut magnitude, radix, digit);
+        }
+
+        Ok(BigInt::from_parts(negative, magnitude))
+    }
+
+    /// Floored division returning `(quotient, remainder)`, where the
+    /// remainder takes the sign of the divisor. `None` if `other` is zero.
+    pub fn div_mod_floor(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (q, r) = div_rem_magnitude(&self.digits, &other.digits);
+        let mut quotient = BigInt::from_parts(self.negative != other.negative, q);
+        let mut remainder = BigInt::from_parts(self.negative, r);
+
+        if !remainder.is_zero() && remainder.negative != other.negative {
+            quotient = &quotient - &BigInt::from(1);
+            remainder = &remainder + other;
+        }
+
+        Some((quotient, remainder))
+    }
+
+    pub fn shift_left(&self, bits: u32) -> BigInt {
+        let mut digits = vec![0; (bits / 32) as usize];
+        digits.extend(shl(&self.digits, bits % 32));
+        BigInt::from_parts(self.negative, digits)
+    }
+
+    /// Arithmetic shift, rounding towards negative infinity like `i64 >>`.
+    pub fn shift_right(&self, bits: u32) -> BigInt {
+        if self.negative {
+            // -x >> n == -((x - 1) >> n) - 1
+            let one = BigInt::from(1);
+            let shifted = (&-self - &one).shift_right(bits);
+            return &-&shifted - &one;
+        }
+
+        let limbs = (bits / 32) as usize;
+        if limbs >= self.digits.len() {
+            return BigInt::zero();
+        }
+        BigInt::from_parts(false, shr(&self.digits[limbs..], bits % 32))
+    }
+
+    /// Applies a bitwise operator digit by digit on the two's complement
+    /// representations, sign extended to a common width.
+    pub fn bitwise(&self, other: &BigInt, op: fn(u32, u32) -> u32) -> BigInt {
+        let len = self.digits.len().max(other.digits.len()) + 1;
+        let a = self.twos_complement(len);
+        let b = other.twos_complement(len);
+        let mut result: Vec<u32> = a.iter().zip(b.iter()).map(|(&x, &y)| op(x, y)).collect();
+
+        if result[len - 1] >> 31 == 1 {
+            negate_twos_complement(&mut result);
+            BigInt::from_parts(true, result)
+        } else {
+            BigInt::from_parts(false, result)
+        }
+    }
+
+    fn twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut digits = self.digits.clone();
+        digits.resize(len, 0);
+        if self.negative {
+            negate_twos_complement(&mut digits);
+        }
+        digits
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::from(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+}
+
+impl Clone for BigInt {
+    fn clone(&self) -> Self {
+        BigInt::from_parts(self.negative, self.digits.clone())
+    }
+}
+
+impl Drop for BigInt {
+    fn drop(&mut self) {
+        LIVE_BYTES.with(|live| live.set(live.get() - self.digits.capacity() * 4));
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> Self {
+        let magnitude = n.unsigned_abs();
+        BigInt::from_parts(n < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        BigInt::from_str_radix(s, 10)
+    }
+}
+
+impl Display for BigInt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        let mut chunks = vec![];
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            chunks.push(div_rem_small(&mut magnitude, DECIMAL_CHUNK));
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        if let Some(first) = chunks.next() {
+            write!(f, "{}", first)?;
+        }
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.digits, &other.digits),
+            (true, true) => cmp_magnitude(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_magnitude(&self.digits, &other.digits));
+        }
+
+        match cmp_magnitude(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::from_parts(other.negative, sub_magnitude(&other.digits, &self.digits))
+            }
+            _ => BigInt::from_parts(self.negative, sub_magnitude(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(
+            self.negative != other.negative,
+            mul_magnitude(&self.digits, &other.digits),
+        )
+    }
+}
+
+fn trim(digits: &mut Vec<u32>) {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+}
+
+fn negate_twos_complement(digits: &mut [u32]) {
+    let mut carry = 1u64;
+    for d in digits.iter_mut() {
+        let t = (!*d) as u64 + carry;
+        *d = t as u32;
+        carry = t >> 32;
+    }
+}
+
+fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, &d) in long.iter().enumerate() {
+        let sum = d as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        result.push(carry as u32);
+    }
+    result
+}
+
+/// Computes `a - b`, requiring `|a| >= |b|`.
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &d) in a.iter().enumerate() {
+        let mut diff = d as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += BASE as i64;
+            borrow = 1;
+        }
+        result.push(diff as u32);
+    }
+    trim(&mut result);
+    result
+}
+
+fn mul_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return vec![];
+    }
+
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(&mut result);
+    result
+}
+
+/// `digits = digits * scale + addend`.
+fn mul_add_small(digits: &mut Vec<u32>, scale: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for d in digits.iter_mut() {
+        let t = *d as u64 * scale as u64 + carry;
+        *d = t as u32;
+        carry = t >> 32;
+    }
+    if carry > 0 {
+        digits.push(carry as u32);
+    }
+}
+
+/// Divides `digits` in place by `divisor`, returning the remainder.
+fn div_rem_small(digits: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for d in digits.iter_mut().rev() {
+        let t = (remainder << 32) | *d as u64;
+        *d = (t / divisor as u64) as u32;
+        remainder = t % divisor as u64;
+    }
+    trim(digits);
+    remainder as u32
+}
+
+/// Truncating division of magnitudes (Knuth, TAOCP vol. 2, algorithm D).
+fn div_rem_magnitude(u: &[u32], v: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_magnitude(u, v) == Ordering::Less {
+        return (vec![], u.to_vec());
+    }
+    if v.len() == 1 {
+        let mut q = u.to_vec();
+        let r = div_rem_small(&mut q, v[0]);
+        return (q, vec![r]);
+    }
+
+    // Normalize so the top digit of the divisor has its high bit set.
+    let shift = v[v.len() - 1].leading_zeros();
+    let vn = shl(v, shift);
+    let mut un = shl(u, shift);
+    un.resize(u.len() + 1, 0);
+
+    let n = vn.len();
+    let m = u.len() - n;
+    let mut q = vec![0u32; m + 1];
+
+    for j in (0..=m).rev() {
+        let num = ((un[j + n] as u64) << 32) | un[j + n - 1] as u64;
+        let mut qhat = num / vn[n - 1] as u64;
+        let mut rhat = num % vn[n - 1] as u64;
+        while qhat >= BASE || qhat * vn[n - 2] as u64 > ((rhat << 32) | un[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += vn[n - 1] as u64;
+            if rhat >= BASE {
+                break;
+            }
+        }
+
+        // Multiply and subtract.
+        let mut k = 0i64;
+        for i in 0..n {
+            let p = qhat * vn[i] as u64;
+            let t = un[i + j] as i64 - k - (p & 0xFFFF_FFFF) as i64;
+            un[i + j] = t as u32;
+            k = (p >> 32) as i64 - (t >> 32);
+        }
+        let t = un[j + n] as i64 - k;
+        un[j + n] = t as u32;
+
+        q[j] = qhat as u32;
+        if t < 0 {
+            // Subtracted too much, add the divisor back.
+            q[j] = q[j].wrapping_sub(1);
+            let mut carry = 0u64;
+            for i in 0..n {
+                let t = un[i + j] as u64 + vn[i] as u64 + carry;
+                un[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            un[j + n] = un[j + n].wrapping_add(carry as u32);
+        }
+    }
+
+    un.truncate(n);
+    let mut r = shr(&un, shift);
+    trim(&mut q);
+    trim(&mut r);
+    (q, r)
+}
+
+fn shl(digits: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return digits.to_vec();
+    }
+    let mut result = Vec::with_capacity(digits.len() + 1);
+    let mut carry = 0u32;
+    for &d in digits {
+        result.push((d << shift) | carry);
+        carry = d >> (32 - shift);
+    }
+    if carry > 0 {
+        result.push(carry);
+    }
+    result
+}
+
+fn shr(digits: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return digits.to_vec();
+    }
+    let mut result = vec![0u32; digits.len()];
+    for i in 0..digits.len() {
+        let high = digits.get(i + 1).map_or(0, |&d| d << (32 - shift));
+        result[i] = (digits[i] >> shift) | high;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    fn check_division(u: &[u32], v: &[u32], q: &[u32], r: &[u32]) {
+        let (quotient, remainder) = div_rem_magnitude(u, v);
+        assert_eq!(quotient, q, "{:x?} / {:x?}", u, v);
+        assert_eq!(remainder, r, "{:x?} % {:x?}", u, v);
+    }
+
+    #[test]
+    fn long_division_corrects_its_estimates() {
+        // (2^128 - 1) / (2^64 - 1), with the divisor already normalized.
+        check_division(&[u32::MAX; 4], &[u32::MAX; 2], &[1, 0, 1], &[]);
+        // The first estimate of the quotient digit is one too high.
+        check_division(
+            &[0x415a_f341, 0x1209_3d26, 0, 0xffff_ffff],
+            &[0xffff_fffe, 0x9990_1c04, 0xffff_ffff],
+            &[0xffff_ffff],
+            &[0x415a_f33f, 0xab99_592d, 0x666f_e3fa],
+        );
+        // Still too high after correcting, so the divisor is added back.
+        check_division(
+            &[0x8000_0000, 0, 0x8000_0000, 0x7fff_ffff],
+            &[0x8000_0000, 0, 0x8000_0000],
+            &[0xffff_fffe],
+            &[0x8000_0000, 0x8000_0001, 0x7fff_ffff],
+        );
+        check_division(
+            &[0xffff_ffff, 0, 0x7fff_ffff, 0xec70_2950, 0x7fff_ffff],
+            &[0xffff_fffe, 0xffff_fffe, 0xffff_ffff],
+            &[0xec70_2950, 0x7fff_ffff],
+            &[0xd8e0_529f, 0xec70_2950, 0xffff_ffff],
+        );
+    }
+
+    #[test]
+    fn division_rounds_towards_negative_infinity() {
+        let cases = [
+            ("-7", "2", "-4", "1"),
+            ("7", "-2", "-4", "-1"),
+            ("-7", "-2", "3", "-1"),
+            ("-8", "2", "-4", "0"),
+            (
+                "-1267650600228229401496703205376",
+                "3",
+                "-422550200076076467165567735126",
+                "2",
+            ),
+            (
+                "1267650600228229401496703205376",
+                "-3",
+                "-422550200076076467165567735126",
+                "-2",
+            ),
+            (
+                "-1267650600228229401496703205376",
+                "-1099511627777",
+                "1152921504605798400",
+                "-1048576",
+            ),
+        ];
+        for (a, b, q, r) in cases {
+            let (quotient, remainder) = big(a).div_mod_floor(&big(b)).unwrap();
+            assert_eq!((quotient, remainder), (big(q), big(r)), "{} / {}", a, b);
+        }
+        assert!(big("1").div_mod_floor(&BigInt::zero()).is_none());
+    }
+
+    #[test]
+    fn shifts_carry_across_digits() {
+        assert_eq!(big("1").shift_left(31), big("2147483648"));
+        assert_eq!(big("1").shift_left(32), big("4294967296"));
+        assert_eq!(
+            big("18446744073709551615").shift_left(1),
+            big("36893488147419103230")
+        );
+        assert_eq!(
+            big("79228162514264337593543950341").shift_right(33),
+            big("9223372036854775808")
+        );
+        assert_eq!(big("4294967296").shift_right(64), BigInt::zero());
+        // Negative values round down, like `i64 >>`.
+        assert_eq!(
+            big("-18446744073709551616").shift_right(1),
+            big("-9223372036854775808")
+        );
+        assert_eq!(big("-18446744073709551617").shift_right(64), big("-2"));
+        assert_eq!(big("-1").shift_right(100), big("-1"));
+    }
+
+    #[test]
+    fn bitwise_ops_act_on_twos_complement() {
+        type Op = fn(u32, u32) -> u32;
+        let cases: [(&str, Op, &str, &str); 5] = [
+            ("-1", |a, b| a & b, "4294967301", "4294967301"),
+            (
+                "-18446744073709551616",
+                |a, b| a & b,
+                "18446744073709551621",
+                "18446744073709551616",
+            ),
+            ("-1099511627776", |a, b| a | b, "255", "-1099511627521"),
+            (
+                "-1180591620717411303424",
+                |a, b| a ^ b,
+                "34359738368",
+                "-1180591620683051565056",
+            ),
+            (
+                "-1180591620717411303424",
+                |a, b| a & b,
+                "-34359738368",
+                "-1180591620717411303424",
+            ),
+        ];
+        for (a, op, b, expected) in cases {
+            assert_eq!(big(a).bitwise(&big(b), op), big(expected), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn display_pads_inner_chunks() {
+        for s in [
+            "0",
+            "-1",
+            "1000000000",
+            "1000000000000000001",
+            "-18446744073709551616",
+        ] {
+            assert_eq!(big(s).to_string(), s);
+        }
+        assert_eq!(big("-0").to_string(), "0");
+    }
+
+    #[test]
+    fn ordering_accounts_for_sign_and_length() {
+        let ascending = [
+            "-18446744073709551616",
+            "-4294967296",
+            "-1",
+            "0",
+            "1",
+            "4294967296",
+            "18446744073709551616",
+        ];
+        for pair in ascending.windows(2) {
+            assert!(big(pair[0]) < big(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::compiler::error::{BytecodeError, BytecodeResult};
+use crate::compiler::object::Function;
+use crate::compiler::verifier::verify;
+use std::convert::TryInto;
+
+/// Starts every `.fbc` file. The leading DEL is a character the lexer
+/// rejects, so a bytecode file is never mistaken for source.
+pub const MAGIC: [u8; 4] = *b"\x7fFBC";
+
+/// Bump whenever the encoding or the instruction set changes, so stale
+/// files are rejected instead of misread.
+pub const VERSION: u16 = 5;
+
+/// How deeply functions may nest in each other's constants. Loading and
+/// verifying recurse once per level, so deeper files are rejected before
+/// they can overflow the native stack.
+pub const MAX_NESTING: usize = 256;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Encodes `fun`, and every function nested in its constants, as a
+/// versioned bytecode file.
+pub fn serialize(fun: &Function) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    fun.serialize(&mut out);
+    out
+}
+
+pub fn deserialize(bytes: &[u8]) -> BytecodeResult<Function> {
+    let mut reader = Reader::new(bytes);
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let fun = Function::deserialize(&mut reader)?;
+    if !reader.is_at_end() {
+        return Err(BytecodeError::TrailingBytes);
+    }
+
+    verify(&fun)?;
+    Ok(fun)
+}
+
+/// Little-endian primitives shared by the `serialize` methods.
+pub fn write_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn write_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Writes a length-prefixed byte string.
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Reads back what the `write_*` functions wrote, failing instead of
+/// panicking on truncated input.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// How many functions are being read, each nested in the one before.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader {
+            bytes,
+            offset: 0,
+            depth: 0,
+        }
+    }
+
+    /// Starts reading a function, nested in any being read already.
+    pub fn enter_function(&mut self) -> BytecodeResult<()> {
+        if self.depth == MAX_NESTING {
+            return Err(BytecodeError::TooDeeplyNested);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave_function(&mut self) {
+        self.depth -= 1;
+    }
+
+    pub fn bytes(&mut self, len: usize) -> BytecodeResult<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> BytecodeResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> BytecodeResult<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> BytecodeResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> BytecodeResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a length-prefixed byte string.
+    pub fn byte_string(&mut self) -> BytecodeResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn string(&mut self) -> BytecodeResult<String> {
+        let bytes = self.byte_string()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidUtf8)
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::compiler::optimizer::OptLevel;
+    use crate::compiler::value::Value;
+    use crate::vm::testing::nested_functions;
+
+    /// The disassembly of `fun` and every function nested in it.
+    fn disassembly(fun: &Function) -> String {
+        let mut out = format!("== {} ==\n{}", fun.name(), fun.chunk());
+        for constant in fun.chunk().constants() {
+            if let Value::Function(nested) = constant {
+                out.push_str(&disassembly(nested));
+            }
+        }
+        out
+    }
+
+    /// A nested function, and ints and floats among its constants.
+    const SOURCE: &str = "def f(a) do\n  a * 2.5 + 1\nend\nx = f(2) ** 100\nputs x\n";
+
+    fn script() -> Vec<u8> {
+        serialize(&compile(SOURCE, OptLevel::O1, &mut vec![]).unwrap())
+    }
+
+    #[test]
+    fn deserialized_code_disassembles_the_same() {
+        let fun = compile(SOURCE, OptLevel::O1, &mut vec![]).unwrap();
+        let loaded = deserialize(&serialize(&fun)).unwrap();
+        assert_eq!(disassembly(&loaded), disassembly(&fun));
+    }
+
+    #[test]
+    fn deserialize_rejects_bad_headers() {
+        let mut bytes = script();
+        bytes[0] = b'#';
+        assert!(matches!(deserialize(&bytes), Err(BytecodeError::BadMagic)));
+
+        let mut bytes = script();
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            deserialize(&bytes),
+            Err(BytecodeError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn deserialize_rejects_truncated_files() {
+        let bytes = script();
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(
+                    deserialize(&bytes[..len]),
+                    Err(BytecodeError::UnexpectedEnd)
+                ),
+                "{} of {} bytes",
+                len,
+                bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_limits_nesting() {
+        let bytes = serialize(&nested_functions(MAX_NESTING + 1));
+        assert!(matches!(
+            deserialize(&bytes),
+            Err(BytecodeError::TooDeeplyNested)
+        ));
+
+        deserialize(&serialize(&nested_functions(MAX_NESTING))).unwrap();
+    }
+}
@@ -1,11 +1,45 @@
+use crate::compiler::bigint::BigInt;
+use crate::compiler::bytecode::{self, Reader};
+use crate::compiler::error::{BytecodeError, BytecodeResult};
+use crate::compiler::object::{Function, Gc};
 use crate::compiler::value::Value;
 use crate::vm::opcode::Opcode;
-use std::fmt::{Display, Formatter, Result};
+use std::collections::HashMap;
+use std::fmt::{Display, Error, Formatter, Result};
+use std::mem;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
     constants: Vec<Value>,
+    constant_indexes: HashMap<ConstantKey, usize>,
+}
+
+/// What makes two constants interchangeable: numbers compare by bit
+/// pattern, strings by content and heap objects by identity.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Int(i64),
+    BigInt(Rc<BigInt>),
+    Float(u64),
+    Bool(bool),
+    String(String),
+    Object(usize),
+}
+
+impl From<&Value> for ConstantKey {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Int(n) => ConstantKey::Int(*n),
+            Value::BigInt(n) => ConstantKey::BigInt(n.clone()),
+            Value::Float(n) => ConstantKey::Float(n.to_bits()),
+            Value::Bool(b) => ConstantKey::Bool(*b),
+            Value::String(s) => ConstantKey::String(s.as_str().to_string()),
+            Value::Closure(closure) => ConstantKey::Object(closure.addr()),
+            Value::Function(fun) => ConstantKey::Object(fun.addr()),
+        }
+    }
 }
 
 impl Chunk {
@@ -13,6 +47,7 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
+            constant_indexes: HashMap::new(),
         }
     }
 
@@ -24,10 +59,23 @@ impl Chunk {
         self.code.push(byte);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
-        // TODO: u8 or usize?
+    /// Returns the index of `value` in the constant pool, only adding it if
+    /// an equal constant isn't there already.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::from(&value);
+        if let Some(&index) = self.constant_indexes.get(&key) {
+            // A fresh string nobody else can see yet, and the heap doesn't
+            // know about.
+            #[cfg(feature = "nan-boxing")]
+            if let Value::String(s) = value {
+                s.free();
+            }
+            return index;
+        }
+
         self.constants.push(value);
-        self.constants.len() as u8 - 1
+        self.constant_indexes.insert(key, self.constants.len() - 1);
+        self.constants.len() - 1
     }
 
     pub fn code(&self) -> &Vec<u8> {
@@ -45,25 +93,173 @@ impl Chunk {
     pub fn constants(&self) -> &[Value] {
         &self.constants
     }
+
+    /// Bytes the chunk holds outside of itself: its code, its constants and
+    /// the text of its strings. Nested functions, heap strings and big
+    /// integers are counted on their own.
+    pub fn heap_size(&self) -> usize {
+        let text: usize = self
+            .constant_indexes
+            .keys()
+            .map(|key| match key {
+                ConstantKey::String(s) => s.capacity(),
+                _ => 0,
+            })
+            .sum();
+        #[cfg(not(feature = "nan-boxing"))]
+        let text = text
+            + self
+                .constants
+                .iter()
+                .map(|constant| match constant {
+                    Value::String(s) => s.capacity(),
+                    _ => 0,
+                })
+                .sum::<usize>();
+
+        self.code.capacity()
+            + self.constants.capacity() * mem::size_of::<Value>()
+            + self.constant_indexes.capacity() * mem::size_of::<(ConstantKey, usize)>()
+            + text
+    }
+
+    /// Renumbers the operands of global slot instructions, slot `n`
+    /// becoming `slots[n]`.
+    pub fn remap_global_slots(&mut self, slots: &[usize]) {
+        let mut offset = 0;
+        while offset < self.code.len() {
+            // Bad code is left as it is, for the verifier or the VM to reject.
+            let opcode = match Opcode::from_byte(self.code[offset]) {
+                Some(opcode) => opcode,
+                None => {
+                    offset += 1;
+                    continue;
+                }
+            };
+            let next = offset + 1 + opcode.operand_len();
+            if matches!(opcode, Opcode::GetGlobalSlot | Opcode::SetGlobalSlot)
+                && next <= self.code.len()
+            {
+                if let Some(&slot) = slots.get(read_long(self, offset + 1)) {
+                    let [_, high, mid, low] = (slot as u32).to_be_bytes();
+                    self.code[offset + 1..next].copy_from_slice(&[high, mid, low]);
+                }
+            }
+            offset = next;
+        }
+    }
+}
+
+// Tags identifying the type of each serialized constant.
+const TAG_INT: u8 = 0;
+const TAG_BIG_INT: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+impl Chunk {
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        bytecode::write_bytes(out, &self.code);
+
+        bytecode::write_u32(out, self.constants.len() as u32);
+        for constant in &self.constants {
+            match constant {
+                Value::Int(n) => {
+                    out.push(TAG_INT);
+                    bytecode::write_u64(out, *n as u64);
+                }
+                Value::BigInt(n) => {
+                    out.push(TAG_BIG_INT);
+                    bytecode::write_bytes(out, n.to_string().as_bytes());
+                }
+                Value::Float(n) => {
+                    out.push(TAG_FLOAT);
+                    bytecode::write_u64(out, n.to_bits());
+                }
+                Value::Bool(b) => {
+                    out.push(TAG_BOOL);
+                    out.push(*b as u8);
+                }
+                Value::String(s) => {
+                    out.push(TAG_STRING);
+                    bytecode::write_bytes(out, s.as_bytes());
+                }
+                Value::Function(fun) => {
+                    out.push(TAG_FUNCTION);
+                    fun.serialize(out);
+                }
+                Value::Closure(_) => unreachable!("closures are only created at runtime"),
+            }
+        }
+    }
+
+    pub fn deserialize(reader: &mut Reader) -> BytecodeResult<Chunk> {
+        let mut chunk = Chunk::new();
+        chunk.code = reader.byte_string()?.to_vec();
+
+        let len = reader.u32()?;
+        for _ in 0..len {
+            let constant = match reader.u8()? {
+                TAG_INT => Value::Int(reader.u64()? as i64),
+                TAG_BIG_INT => {
+                    let digits = reader.string()?;
+                    let n = digits
+                        .parse::<BigInt>()
+                        .map_err(|_| BytecodeError::InvalidNumber(digits))?;
+                    Value::BigInt(Rc::new(n))
+                }
+                TAG_FLOAT => Value::Float(f64::from_bits(reader.u64()?)),
+                TAG_BOOL => Value::Bool(reader.u8()? != 0),
+                TAG_STRING => Value::string(reader.string()?),
+                TAG_FUNCTION => Value::Function(Gc::new(Function::deserialize(reader)?)),
+                tag => return Err(BytecodeError::InvalidConstantTag(tag)),
+            };
+
+            // Keep the file's indexes even if it holds duplicates.
+            chunk
+                .constant_indexes
+                .entry(ConstantKey::from(&constant))
+                .or_insert(chunk.constants.len());
+            chunk.constants.push(constant);
+        }
+
+        Ok(chunk)
+    }
 }
 
 impl Display for Chunk {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = disassemble_instruction(f, self, &mut offset);
+            offset = disassemble_instruction(f, self, offset)?;
         }
 
-        write!(f, "")
+        Ok(())
     }
 }
 
-fn disassemble_instruction(f: &mut Formatter<'_>, chunk: &Chunk, offset: &mut usize) -> usize {
-    write!(f, "{:04X}", offset);
+fn disassemble_instruction(
+    f: &mut Formatter<'_>,
+    chunk: &Chunk,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    write!(f, "{:04X}", offset)?;
+
+    write!(f, "   | ")?;
 
-    write!(f, "   | ");
+    let instruction = match Opcode::from_byte(chunk.code[offset]) {
+        Some(instruction) => instruction,
+        None => {
+            writeln!(f, "UNKNOWN {:#04x}", chunk.code[offset])?;
+            return Ok(offset + 1);
+        }
+    };
+    if offset + 1 + instruction.operand_len() > chunk.code.len() {
+        writeln!(f, "{:?} (truncated)", instruction)?;
+        return Ok(chunk.code.len());
+    }
 
-    let instruction = Opcode::from(chunk.code[*offset]);
     match instruction {
         Opcode::Return => simple_instruction(f, "RETURN", offset),
         Opcode::Constant => constant_instruction(chunk, f, "CONSTANT", offset),
@@ -71,45 +267,144 @@ fn disassemble_instruction(f: &mut Formatter<'_>, chunk: &Chunk, offset: &mut us
         Opcode::Subtract => simple_instruction(f, "SUBTRACT", offset),
         Opcode::Multiply => simple_instruction(f, "MULTIPLY", offset),
         Opcode::Divide => simple_instruction(f, "DIVIDE", offset),
-        Opcode::SetGlobal => constant_instruction(chunk, f, "SET_GLOBAL", offset),
-        Opcode::GetGlobal => constant_instruction(chunk, f, "GET_GLOBAL", offset),
+        Opcode::Modulo => simple_instruction(f, "MODULO", offset),
+        Opcode::Power => simple_instruction(f, "POWER", offset),
+        Opcode::BitAnd => simple_instruction(f, "BIT_AND", offset),
+        Opcode::BitOr => simple_instruction(f, "BIT_OR", offset),
+        Opcode::BitXor => simple_instruction(f, "BIT_XOR", offset),
+        Opcode::ShiftLeft => simple_instruction(f, "SHIFT_LEFT", offset),
+        Opcode::ShiftRight => simple_instruction(f, "SHIFT_RIGHT", offset),
+        Opcode::Negate => simple_instruction(f, "NEGATE", offset),
+        Opcode::BitNot => simple_instruction(f, "BIT_NOT", offset),
         Opcode::GetLocal => byte_instruction(chunk, f, "GET_LOCAL", offset),
         Opcode::SetLocal => byte_instruction(chunk, f, "SET_LOCAL", offset),
         Opcode::Puts => simple_instruction(f, "PUTS", offset),
-        Opcode::Closure => {
-            // TODO: Clean up.
-            *offset += 2;
-
-            let constant = chunk.code[*offset - 1];
-            write!(f, "{:-16} {:4} ", "CLOSURE", constant);
-            writeln!(f, "'{:?}'", chunk.constants()[constant as usize]);
-
-            *offset
-        }
+        Opcode::Closure => constant_instruction(chunk, f, "CLOSURE", offset),
         Opcode::Call => byte_instruction(chunk, f, "CALL", offset),
         Opcode::Pop => simple_instruction(f, "POP", offset),
+        Opcode::Jump => jump_instruction(chunk, f, "JUMP", offset),
+        Opcode::JumpIfFalse => jump_instruction(chunk, f, "JUMP_IF_FALSE", offset),
+        Opcode::ConstantLong => constant_long_instruction(chunk, f, "CONSTANT_LONG", offset),
+        Opcode::GetLocalLong => long_instruction(chunk, f, "GET_LOCAL_LONG", offset),
+        Opcode::SetLocalLong => long_instruction(chunk, f, "SET_LOCAL_LONG", offset),
+        Opcode::ClosureLong => constant_long_instruction(chunk, f, "CLOSURE_LONG", offset),
+        Opcode::SetLocalPop => byte_instruction(chunk, f, "SET_LOCAL_POP", offset),
+        Opcode::AddConstant => constant_instruction(chunk, f, "ADD_CONSTANT", offset),
+        Opcode::GetGlobalSlot => long_instruction(chunk, f, "GET_GLOBAL_SLOT", offset),
+        Opcode::SetGlobalSlot => long_instruction(chunk, f, "SET_GLOBAL_SLOT", offset),
+        Opcode::TailCall => byte_instruction(chunk, f, "TAIL_CALL", offset),
     }
 }
 
-fn simple_instruction(f: &mut Formatter<'_>, name: &str, offset: &mut usize) -> usize {
-    writeln!(f, "{}", name);
-    *offset + 1
+fn simple_instruction(
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    writeln!(f, "{}", name)?;
+    Ok(offset + 1)
 }
 
 fn constant_instruction(
     chunk: &Chunk,
     f: &mut Formatter<'_>,
     name: &str,
-    offset: &mut usize,
-) -> usize {
-    let constant = chunk.code()[*offset + 1];
-    write!(f, "{:-16} {:4} ", name, constant);
-    writeln!(f, "'{}'", chunk.constants()[constant as usize]);
-    *offset + 2
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    let constant = chunk.code()[offset + 1];
+    write!(f, "{:-16} {:4} ", name, constant)?;
+    writeln!(f, "'{}'", chunk.constants()[constant as usize])?;
+    Ok(offset + 2)
+}
+
+fn constant_long_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    let constant = read_long(chunk, offset + 1);
+    write!(f, "{:-16} {:4} ", name, constant)?;
+    writeln!(f, "'{}'", chunk.constants()[constant])?;
+    Ok(offset + 4)
+}
+
+fn long_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    let slot = read_long(chunk, offset + 1);
+    writeln!(f, "{:-16} {:4X}", name, slot)?;
+    Ok(offset + 4)
+}
+
+/// Reads a 24 bit big-endian operand, as used by the `*Long` instructions.
+fn read_long(chunk: &Chunk, offset: usize) -> usize {
+    u32::from_be_bytes([
+        0,
+        chunk.code[offset],
+        chunk.code[offset + 1],
+        chunk.code[offset + 2],
+    ]) as usize
 }
 
-fn byte_instruction(chunk: &Chunk, f: &mut Formatter<'_>, name: &str, offset: &mut usize) -> usize {
-    let slot = chunk.code[*offset + 1];
-    writeln!(f, "{:-16} {:4X}", name, slot);
-    *offset + 2
+fn byte_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    let slot = chunk.code[offset + 1];
+    writeln!(f, "{:-16} {:4X}", name, slot)?;
+    Ok(offset + 2)
+}
+
+fn jump_instruction(
+    chunk: &Chunk,
+    f: &mut Formatter<'_>,
+    name: &str,
+    offset: usize,
+) -> std::result::Result<usize, Error> {
+    let jump = u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]) as usize;
+    writeln!(f, "{:-16} {:4X} -> {:04X}", name, offset, offset + 3 + jump)?;
+    Ok(offset + 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_constants_share_a_slot() {
+        let mut chunk = Chunk::new();
+        let int = chunk.add_constant(Value::Int(1));
+        let float = chunk.add_constant(Value::Float(2.5));
+        let string = chunk.add_constant(Value::string("x".to_string()));
+        assert_eq!(chunk.add_constant(Value::Int(1)), int);
+        assert_eq!(chunk.add_constant(Value::Float(2.5)), float);
+        assert_eq!(chunk.add_constant(Value::string("x".to_string())), string);
+        assert_eq!(chunk.constants().len(), 3);
+    }
+
+    #[test]
+    fn constants_only_share_a_slot_with_the_same_bits() {
+        let mut chunk = Chunk::new();
+        let values = [
+            Value::Int(1),
+            Value::Float(1.0),
+            Value::Float(0.0),
+            Value::Float(-0.0),
+            Value::Float(f64::NAN),
+            Value::Float(-f64::NAN),
+        ];
+        for (index, value) in values.iter().enumerate() {
+            assert_eq!(chunk.add_constant(value.clone()), index);
+        }
+
+        // A NaN isn't equal to itself, but the same NaN is the same constant.
+        assert_eq!(chunk.add_constant(Value::Float(f64::NAN)), 4);
+        assert_eq!(chunk.constants().len(), values.len());
+    }
 }
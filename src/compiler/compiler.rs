@@ -5,10 +5,15 @@ use crate::compiler::object::{Function, FunctionType};
 use crate::compiler::value::Value;
 use crate::parser::ast::Identifier;
 use crate::vm::opcode::Opcode;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
 pub struct Compiler {
     current: CompilerInstance,
     errors: Vec<CompilerError>,
+    /// Every global the script mentions, numbered in order of appearance.
+    global_names: Vec<String>,
+    global_slots: HashMap<String, usize>,
 }
 
 impl Compiler {
@@ -16,16 +21,18 @@ impl Compiler {
         Compiler {
             current: CompilerInstance::new(FunctionType::Script),
             errors: vec![],
+            global_names: vec![],
+            global_slots: HashMap::new(),
         }
     }
 
     pub fn declare_variable(&mut self, ident: &Identifier) {
         if self.is_scoped() {
-            if self.contains_local_in_current_scope(&ident) {
+            if self.contains_local_in_current_scope(ident) {
                 self.add_error(CompilerError::LocalAlreadyDefined);
             }
 
-            self.add_local(&ident);
+            self.add_local(ident);
         }
     }
 
@@ -35,11 +42,26 @@ impl Compiler {
             return;
         }
 
-        self.emit(Opcode::SetGlobal);
-        let constant_id = self
-            .current_chunk()
-            .add_constant(Value::String(name.to_string()));
-        self.emit_byte(constant_id);
+        let slot = self.global_slot(name);
+        self.emit_global_op(Opcode::SetGlobalSlot, slot);
+    }
+
+    /// The slot of the global `name`. Globals get their slot when they're
+    /// first mentioned, so functions can refer to ones defined later.
+    pub fn global_slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
+        }
+
+        self.global_names.push(name.to_string());
+        self.global_slots
+            .insert(name.to_string(), self.global_names.len() - 1);
+        self.global_names.len() - 1
+    }
+
+    pub fn take_global_names(&mut self) -> Vec<String> {
+        self.global_slots.clear();
+        std::mem::take(&mut self.global_names)
     }
 
     pub fn resolve_local(&mut self, name: &str) -> Option<usize> {
@@ -71,9 +93,7 @@ impl Compiler {
         self.emit_return();
         let fun_copy = self.current.function().clone();
 
-        println!("{}", self.current_chunk());
-
-        if let Some(enclosing) = *self.current.enclosing().clone() {
+        if let Some(enclosing) = self.current.enclosing().clone() {
             self.current = enclosing;
         }
         fun_copy
@@ -100,19 +120,87 @@ impl Compiler {
         self.errors.push(error);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.current_chunk().add_constant(value)
     }
 
+    pub fn take_errors(&mut self) -> Vec<CompilerError> {
+        std::mem::take(&mut self.errors)
+    }
+
     pub fn emit_return(&mut self) {
         // self.emit(Opcode::Nil); // TODO: Return Nil???
         self.emit(Opcode::Return);
     }
 
     pub fn emit_constant(&mut self, value: Value) {
-        let constant = self.current_chunk().add_constant(value);
-        self.emit(Opcode::Constant);
-        self.emit_byte(constant);
+        let constant = self.add_constant(value);
+        self.emit_constant_op(Opcode::Constant, Opcode::ConstantLong, constant);
+    }
+
+    /// Emits `short` with a one byte constant index, or `long` with a three
+    /// byte one once the chunk has more than 256 constants.
+    pub fn emit_constant_op(&mut self, short: Opcode, long: Opcode, constant: usize) {
+        if !self.emit_operand(short, long, constant) {
+            self.add_error(CompilerError::TooManyConstants);
+        }
+    }
+
+    /// Like `emit_constant_op`, but for local slots.
+    pub fn emit_local_op(&mut self, short: Opcode, long: Opcode, slot: usize) {
+        if !self.emit_operand(short, long, slot) {
+            self.add_error(CompilerError::TooManyLocals);
+        }
+    }
+
+    /// Emits a global slot instruction, which always takes three bytes.
+    pub fn emit_global_op(&mut self, opcode: Opcode, slot: usize) {
+        if slot >= 1 << 24 {
+            self.add_error(CompilerError::TooManyGlobals);
+        }
+
+        self.emit(opcode);
+        let [_, high, mid, low] = (slot as u32).to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(mid);
+        self.emit_byte(low);
+    }
+
+    /// Returns false if `operand` doesn't even fit in three bytes.
+    fn emit_operand(&mut self, short: Opcode, long: Opcode, operand: usize) -> bool {
+        if let Ok(operand) = u8::try_from(operand) {
+            self.emit(short);
+            self.emit_byte(operand);
+            return true;
+        }
+
+        self.emit(long);
+        let [_, high, mid, low] = (operand as u32).to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(mid);
+        self.emit_byte(low);
+        operand < 1 << 24
+    }
+
+    /// Emits a jump with a placeholder offset, returning the position of the
+    /// offset for `patch_jump`.
+    pub fn emit_jump(&mut self, opcode: Opcode) -> usize {
+        self.emit(opcode);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().code().len() - 2
+    }
+
+    /// Points the jump at `offset` to the next instruction to be emitted.
+    pub fn patch_jump(&mut self, offset: usize) {
+        let jump = self.current_chunk().code().len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.add_error(CompilerError::JumpTooLarge);
+        }
+
+        let code = self.current_chunk().code_mut();
+        code[offset] = (jump >> 8) as u8;
+        code[offset + 1] = jump as u8;
     }
 
     pub fn emit(&mut self, opcode: Opcode) {
@@ -126,10 +214,109 @@ impl Compiler {
     pub fn set_instance(&mut self, instance: CompilerInstance) {
         let current_copy = self.current.clone();
         self.current = instance;
-        *self.current.enclosing_mut() = Box::new(Some(current_copy));
+        **self.current.enclosing_mut() = Some(current_copy);
     }
 
     pub fn current_chunk(&mut self) -> &mut Chunk {
         self.current.function_mut().chunk_mut()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::compiler::optimizer::OptLevel;
+    use crate::compiler::verifier::verify;
+    use crate::vm::testing::{output, run, vm};
+
+    fn uses(fun: &Function, opcode: Opcode) -> bool {
+        fun.chunk().code().contains(&(opcode as u8))
+    }
+
+    #[test]
+    fn constants_past_256_use_long_operands() {
+        let source: String = (0..300).map(|n| format!("puts {}\n", n)).collect();
+        let fun = compile(&source, OptLevel::O1, &mut vec![]).unwrap();
+        assert!(uses(&fun, Opcode::ConstantLong));
+
+        let mut vm = vm();
+        run(&mut vm, &source).unwrap();
+        let expected: String = (0..300).map(|n| format!("{}\n", n)).collect();
+        assert_eq!(output(&mut vm), expected);
+    }
+
+    #[test]
+    fn locals_past_256_use_long_operands() {
+        // Parameters stop short of 256 slots, so fill the stack by hand.
+        let mut compiler = Compiler::new();
+        for n in 0..300 {
+            compiler.emit_constant(Value::Int(n));
+        }
+        compiler.emit_constant(Value::Int(-1));
+        compiler.emit_local_op(Opcode::SetLocal, Opcode::SetLocalLong, 280);
+        compiler.emit(Opcode::Pop);
+        for slot in [280, 281] {
+            compiler.emit_local_op(Opcode::GetLocal, Opcode::GetLocalLong, slot);
+            compiler.emit(Opcode::Puts);
+        }
+        compiler.emit_constant(Value::Int(0));
+        assert!(compiler.take_errors().is_empty());
+
+        let fun = compiler.end_compiler();
+        assert!(uses(&fun, Opcode::SetLocalLong));
+        assert!(uses(&fun, Opcode::GetLocalLong));
+        verify(&fun).unwrap();
+
+        let mut vm = vm();
+        vm.interpret(fun).unwrap();
+        // Slot zero holds the script itself.
+        assert_eq!(output(&mut vm), "-1\n280\n");
+    }
+
+    #[test]
+    fn operands_are_limited_to_three_bytes() {
+        let mut compiler = Compiler::new();
+        compiler.emit_constant_op(Opcode::Constant, Opcode::ConstantLong, (1 << 24) - 1);
+        compiler.emit_local_op(Opcode::GetLocal, Opcode::GetLocalLong, (1 << 24) - 1);
+        assert!(compiler.take_errors().is_empty());
+
+        compiler.emit_constant_op(Opcode::Constant, Opcode::ConstantLong, 1 << 24);
+        compiler.emit_local_op(Opcode::GetLocal, Opcode::GetLocalLong, 1 << 24);
+        assert!(matches!(
+            compiler.take_errors()[..],
+            [
+                CompilerError::TooManyConstants,
+                CompilerError::TooManyLocals
+            ]
+        ));
+    }
+
+    /// Defines `f` with `params` parameters and calls it with `args`
+    /// arguments.
+    fn call_source(params: usize, args: usize) -> String {
+        let params: Vec<_> = (0..params).map(|n| format!("a{}", n)).collect();
+        let args: Vec<_> = (0..args).map(|n| n.to_string()).collect();
+        format!(
+            "def f({}) do\n  a254\nend\nputs f({})\n",
+            params.join(", "),
+            args.join(", ")
+        )
+    }
+
+    #[test]
+    fn functions_take_at_most_255_arguments() {
+        let mut vm = vm();
+        run(&mut vm, &call_source(255, 255)).unwrap();
+        assert_eq!(output(&mut vm), "254\n");
+
+        assert!(matches!(
+            compile(&call_source(256, 255), OptLevel::O1, &mut vec![]),
+            Err(CompilerError::TooManyParameters)
+        ));
+        assert!(matches!(
+            compile(&call_source(255, 256), OptLevel::O1, &mut vec![]),
+            Err(CompilerError::TooManyArguments)
+        ));
+    }
+}
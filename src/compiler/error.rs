@@ -1,7 +1,82 @@
+use crate::lexer::error::SyntaxError;
+use crate::parser::error::ParserError;
+use std::fmt::{Display, Formatter, Result};
+
 pub type CompileResult<T> = std::result::Result<T, CompilerError>;
 
+// Fields are only ever read by `Debug`, when errors are reported.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum CompilerError {
     LocalAlreadyDefined,
     LocalNotInitialized,
+    JumpTooLarge,
+    TooManyConstants,
+    TooManyGlobals,
+    TooManyLocals,
+    TooManyParameters,
+    TooManyArguments,
+    Syntax(SyntaxError),
+    Parse(ParserError),
+}
+
+/// Problems worth pointing out that still leave a runnable program.
+#[derive(Debug)]
+pub enum CompilerWarning {
+    DivisionByZero,
+}
+
+impl Display for CompilerWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            CompilerWarning::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+pub type BytecodeResult<T> = std::result::Result<T, BytecodeError>;
+
+/// Why a bytecode file couldn't be loaded.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidUtf8,
+    InvalidConstantTag(u8),
+    InvalidNumber(String),
+    /// Functions nested more than `bytecode::MAX_NESTING` deep.
+    TooDeeplyNested,
+    Verify(String, VerifyError),
+}
+
+/// Why the verifier rejected a function. Offsets point at the offending
+/// instruction, or at the target of a bad jump.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum VerifyError {
+    InvalidOpcode(usize, u8),
+    TruncatedInstruction(usize),
+    ConstantOutOfRange(usize),
+    LocalOutOfRange(usize),
+    GlobalOutOfRange(usize),
+    JumpIntoInstruction(usize),
+    JumpOutOfRange(usize),
+    StackUnderflow(usize),
+    InconsistentStack(usize),
+    FallsOffEnd,
+}
+
+impl From<SyntaxError> for CompilerError {
+    fn from(error: SyntaxError) -> Self {
+        CompilerError::Syntax(error)
+    }
+}
+
+impl From<ParserError> for CompilerError {
+    fn from(error: ParserError) -> Self {
+        CompilerError::Parse(error)
+    }
 }
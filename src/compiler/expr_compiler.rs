@@ -1,36 +1,55 @@
 use crate::compiler::compiler::Compiler;
+use crate::compiler::error::CompilerError;
 use crate::compiler::instance::CompilerInstance;
 use crate::compiler::object::{FunctionType, Gc};
 use crate::compiler::value::Value;
 use crate::parser::ast::*;
 use crate::vm::opcode::Opcode;
+use std::rc::Rc;
 
 pub fn compile_expr(c: &mut Compiler, expr: Expr) {
     match expr {
-        Expr::Binary { left, op, right } => compile_binary(c, left, op, right),
+        Expr::Binary { left, op, right } => compile_binary(c, *left, op, *right),
+        Expr::Unary { op, expr } => compile_unary(c, op, *expr),
         Expr::Fun { name, decl } => compile_fun(c, name, decl),
-        Expr::Call { callee, args } => compile_call(c, callee, args),
-        Expr::VarSet { name, value } => compile_var_set(c, name, value),
+        Expr::Call { callee, args } => compile_call(c, *callee, args, false),
+        Expr::VarSet { name, op, value } => compile_var_set(c, name, op, *value),
         Expr::VarGet { name } => compile_var_get(c, name),
-        Expr::Block { block } => compile_block(c, block),
+        Expr::Block { block } => compile_block(c, *block, false),
         Expr::Literal(l) => compile_literal(c, l),
-        Expr::Puts { value } => compile_puts(c, value),
+        Expr::Puts { value } => compile_puts(c, *value),
     }
 }
 
-fn compile_binary(compiler: &mut Compiler, left: Box<Expr>, op: BinaryOperator, right: Box<Expr>) {
-    compile_expr(compiler, *left);
-    compile_expr(compiler, *right);
+fn compile_binary(compiler: &mut Compiler, left: Expr, op: BinaryOperator, right: Expr) {
+    compile_expr(compiler, left);
+    compile_expr(compiler, right);
 
     match op {
         BinaryOperator::Add => compiler.emit(Opcode::Add),
         BinaryOperator::Subtract => compiler.emit(Opcode::Subtract),
         BinaryOperator::Multiply => compiler.emit(Opcode::Multiply),
         BinaryOperator::Divide => compiler.emit(Opcode::Divide),
+        BinaryOperator::Modulo => compiler.emit(Opcode::Modulo),
+        BinaryOperator::Power => compiler.emit(Opcode::Power),
+        BinaryOperator::BitAnd => compiler.emit(Opcode::BitAnd),
+        BinaryOperator::BitOr => compiler.emit(Opcode::BitOr),
+        BinaryOperator::BitXor => compiler.emit(Opcode::BitXor),
+        BinaryOperator::ShiftLeft => compiler.emit(Opcode::ShiftLeft),
+        BinaryOperator::ShiftRight => compiler.emit(Opcode::ShiftRight),
         _ => todo!(),
     }
 }
 
+fn compile_unary(compiler: &mut Compiler, op: UnaryOperator, expr: Expr) {
+    compile_expr(compiler, expr);
+
+    match op {
+        UnaryOperator::Negate => compiler.emit(Opcode::Negate),
+        UnaryOperator::BitNot => compiler.emit(Opcode::BitNot),
+    }
+}
+
 fn compile_fun(compiler: &mut Compiler, name: Identifier, decl: FunDecl) {
     compiler.set_instance(CompilerInstance::new(FunctionType::Function));
     compile_closure(compiler, &name, decl);
@@ -41,6 +60,9 @@ fn compile_closure(compiler: &mut Compiler, name: &Identifier, decl: FunDecl) {
     compiler.begin_scope();
 
     let arity = decl.args().len();
+    if arity > u8::MAX as usize {
+        compiler.add_error(CompilerError::TooManyParameters);
+    }
 
     // Compile function arguments.
     for arg in decl.args() {
@@ -48,75 +70,128 @@ fn compile_closure(compiler: &mut Compiler, name: &Identifier, decl: FunDecl) {
         compiler.define_variable(arg);
     }
 
-    // Compile function body.
-    compile_expr(compiler, Expr::block(decl.body()));
+    // Compile function body. Its value is returned right away.
+    compile_block(compiler, decl.body(), true);
 
     // Create closure object.
     let mut closure = compiler.end_compiler();
     closure.set_name(name.clone());
     closure.set_arity(arity as u8);
 
-    compiler.emit(Opcode::Closure);
-
     let constant_id = compiler.add_constant(Value::Function(Gc::new(closure)));
-    compiler.emit_byte(constant_id);
+    compiler.emit_constant_op(Opcode::Closure, Opcode::ClosureLong, constant_id);
 }
 
-fn compile_call(compiler: &mut Compiler, callee: Box<Expr>, args: Vec<Expr>) {
+/// A call in tail position replaces the calling frame instead of returning
+/// to it, so recursion through tail calls runs in constant stack space.
+fn compile_call(compiler: &mut Compiler, callee: Expr, args: Vec<Expr>, tail: bool) {
     let arity = args.len();
-    compile_expr(compiler, *callee);
+    if arity > u8::MAX as usize {
+        compiler.add_error(CompilerError::TooManyArguments);
+    }
+
+    compile_expr(compiler, callee);
     for arg in args {
         compile_expr(compiler, arg);
     }
-    compiler.emit(Opcode::Call);
+    compiler.emit(if tail { Opcode::TailCall } else { Opcode::Call });
     compiler.emit_byte(arity as u8);
 }
 
-fn compile_var_set(compiler: &mut Compiler, name: Identifier, value: Box<Expr>) {
-    compile_expr(compiler, *value);
+fn compile_var_set(compiler: &mut Compiler, name: Identifier, op: AssignOperator, value: Expr) {
+    let op = match op {
+        AssignOperator::Assign => {
+            compile_expr(compiler, value);
+            return emit_var_set(compiler, name);
+        }
+        AssignOperator::Or => return compile_var_or_set(compiler, name, value),
+        AssignOperator::Add => Opcode::Add,
+        AssignOperator::Subtract => Opcode::Subtract,
+        AssignOperator::Multiply => Opcode::Multiply,
+        AssignOperator::Divide => Opcode::Divide,
+    };
+
+    compile_var_get(compiler, name.clone());
+    compile_expr(compiler, value);
+    compiler.emit(op);
+    emit_var_set(compiler, name);
+}
+
+fn compile_var_or_set(compiler: &mut Compiler, name: Identifier, value: Expr) {
+    compile_var_get(compiler, name.clone());
+    let assign_jump = compiler.emit_jump(Opcode::JumpIfFalse);
+
+    // SetGlobalSlot pops the assigned value while SetLocal leaves it on the
+    // stack, so the current value has to match on the skipping path.
+    if compiler.resolve_local(&name).is_none() {
+        compiler.emit(Opcode::Pop);
+    }
+    let end_jump = compiler.emit_jump(Opcode::Jump);
+
+    compiler.patch_jump(assign_jump);
+    compiler.emit(Opcode::Pop);
+    compile_expr(compiler, value);
+    emit_var_set(compiler, name);
+
+    compiler.patch_jump(end_jump);
+}
 
+fn emit_var_set(compiler: &mut Compiler, name: Identifier) {
     if let Some(local) = compiler.resolve_local(&name) {
         // Local variable
-        compiler.emit(Opcode::SetLocal);
-        compiler.emit_byte(local as u8);
+        compiler.emit_local_op(Opcode::SetLocal, Opcode::SetLocalLong, local);
     } else {
         // Global variable
-        compiler.emit(Opcode::SetGlobal);
-        let constant_id = compiler.add_constant(Value::String(name));
-        compiler.emit_byte(constant_id);
+        let slot = compiler.global_slot(&name);
+        compiler.emit_global_op(Opcode::SetGlobalSlot, slot);
     }
 }
 
 fn compile_var_get(compiler: &mut Compiler, name: Identifier) {
     if let Some(local) = compiler.resolve_local(&name) {
         // Local variable
-        compiler.emit(Opcode::GetLocal);
-        compiler.emit_byte(local as u8);
+        compiler.emit_local_op(Opcode::GetLocal, Opcode::GetLocalLong, local);
     } else {
         // Global variable
-        compiler.emit(Opcode::GetGlobal);
-        let constant_id = compiler.add_constant(Value::String(name));
-        compiler.emit_byte(constant_id);
+        let slot = compiler.global_slot(&name);
+        compiler.emit_global_op(Opcode::GetGlobalSlot, slot);
     }
 }
 
-fn compile_block(compiler: &mut Compiler, block: Box<BlockDecl>) {
+/// When `tail` is set the block's value is returned right away, making its
+/// last expression a tail position.
+fn compile_block(compiler: &mut Compiler, block: BlockDecl, tail: bool) {
+    let len = block.len();
     compiler.begin_scope();
-    for expr in *block {
-        compile_expr(compiler, expr);
+    for (i, expr) in block.into_iter().enumerate() {
+        if tail && i + 1 == len {
+            compile_tail_expr(compiler, expr);
+        } else {
+            compile_expr(compiler, expr);
+        }
     }
     compiler.end_scope();
 }
 
+fn compile_tail_expr(compiler: &mut Compiler, expr: Expr) {
+    match expr {
+        Expr::Call { callee, args } => compile_call(compiler, *callee, args, true),
+        Expr::Block { block } => compile_block(compiler, *block, true),
+        expr => compile_expr(compiler, expr),
+    }
+}
+
 fn compile_literal(compiler: &mut Compiler, literal: LiteralExpr) {
     match literal {
-        LiteralExpr::Number(n) => compiler.emit_constant(Value::Number(n)),
+        LiteralExpr::Int(n) => compiler.emit_constant(Value::Int(n)),
+        LiteralExpr::BigInt(n) => compiler.emit_constant(Value::BigInt(Rc::new(n))),
+        LiteralExpr::Float(n) => compiler.emit_constant(Value::Float(n)),
         LiteralExpr::True => compiler.emit_constant(Value::Bool(true)),
         LiteralExpr::False => compiler.emit_constant(Value::Bool(false)),
     }
 }
 
-fn compile_puts(compiler: &mut Compiler, value: Box<Expr>) {
-    compile_expr(compiler, *value);
+fn compile_puts(compiler: &mut Compiler, value: Expr) {
+    compile_expr(compiler, value);
     compiler.emit(Opcode::Puts);
 }
@@ -0,0 +1,215 @@
+use crate::compiler::error::CompilerWarning;
+use crate::compiler::value::Value;
+use crate::parser::ast::*;
+use crate::vm::error::RuntimeError;
+use std::rc::Rc;
+
+/// Largest integer result, in bytes of digits, worth folding. Anything
+/// bigger would bloat the bytecode and slow down compiling, so it's left
+/// for the VM to compute when, and if, it runs.
+pub const MAX_FOLDED_SIZE: usize = 1024;
+
+/// Simplifies the AST before it's compiled: operators applied to literals
+/// are evaluated, and statements that only produce an unused value without
+/// side effects are dropped.
+pub fn fold(ast: Vec<Expr>, warnings: &mut Vec<CompilerWarning>) -> Vec<Expr> {
+    let mut folder = Folder { warnings };
+
+    // The script's own result is never used.
+    ast.into_iter()
+        .map(|expr| folder.fold_expr(expr))
+        .filter(|expr| !is_pure(expr))
+        .collect()
+}
+
+struct Folder<'a> {
+    warnings: &'a mut Vec<CompilerWarning>,
+}
+
+impl Folder<'_> {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Binary { left, op, right } => {
+                let left = self.fold_expr(*left);
+                let right = self.fold_expr(*right);
+                self.fold_binary(left, op, right)
+            }
+            Expr::Unary { op, expr } => {
+                let expr = self.fold_expr(*expr);
+                self.fold_unary(op, expr)
+            }
+            Expr::Fun { name, decl } => {
+                let args = decl.args().clone();
+                Expr::fun(name, FunDecl::new(args, self.fold_body(decl.body())))
+            }
+            Expr::Call { callee, args } => {
+                let callee = self.fold_expr(*callee);
+                Expr::call(
+                    callee,
+                    args.into_iter().map(|arg| self.fold_expr(arg)).collect(),
+                )
+            }
+            Expr::VarSet { name, op, value } => {
+                Expr::compound_set(name, op, self.fold_expr(*value))
+            }
+            Expr::Block { block } => Expr::block(self.fold_body(*block)),
+            Expr::Puts { value } => Expr::puts(self.fold_expr(*value)),
+            Expr::VarGet { .. } | Expr::Literal(_) => expr,
+        }
+    }
+
+    /// Folds a function body. Its last expression is the return value, so
+    /// that one is kept even when it's pure.
+    fn fold_body(&mut self, body: BlockDecl) -> BlockDecl {
+        let len = body.len();
+        body.into_iter()
+            .map(|expr| self.fold_expr(expr))
+            .enumerate()
+            .filter(|(i, expr)| *i + 1 == len || !is_pure(expr))
+            .map(|(_, expr)| expr)
+            .collect()
+    }
+
+    fn fold_binary(&mut self, left: Expr, op: BinaryOperator, right: Expr) -> Expr {
+        let (a, b) = match (&left, &right) {
+            (Expr::Literal(a), Expr::Literal(b)) => (to_value(a), to_value(b)),
+            _ => return Expr::binary(left, op, right),
+        };
+
+        let size = match op {
+            BinaryOperator::Multiply => a.product_size(&b),
+            BinaryOperator::Power => a.power_size(&b),
+            BinaryOperator::ShiftLeft => a.shift_size(&b),
+            _ => 0,
+        };
+        if size > MAX_FOLDED_SIZE {
+            return Expr::binary(left, op, right);
+        }
+
+        let result = match op {
+            BinaryOperator::Add => a + b,
+            BinaryOperator::Subtract => a - b,
+            BinaryOperator::Multiply => a * b,
+            BinaryOperator::Divide => a / b,
+            BinaryOperator::Modulo => a % b,
+            BinaryOperator::Power => a.pow(b),
+            BinaryOperator::BitAnd => a & b,
+            BinaryOperator::BitOr => a | b,
+            BinaryOperator::BitXor => a ^ b,
+            BinaryOperator::ShiftLeft => a << b,
+            BinaryOperator::ShiftRight => a >> b,
+            _ => return Expr::binary(left, op, right),
+        };
+
+        // Anything that fails is left for the VM to raise at runtime.
+        match result {
+            Ok(value) => to_literal(value).unwrap_or_else(|| Expr::binary(left, op, right)),
+            Err(RuntimeError::DivisionByZero) => {
+                self.warnings.push(CompilerWarning::DivisionByZero);
+                Expr::binary(left, op, right)
+            }
+            Err(_) => Expr::binary(left, op, right),
+        }
+    }
+
+    fn fold_unary(&mut self, op: UnaryOperator, expr: Expr) -> Expr {
+        let a = match &expr {
+            Expr::Literal(a) => to_value(a),
+            _ => return Expr::unary(op, expr),
+        };
+
+        let result = match op {
+            UnaryOperator::Negate => -a,
+            UnaryOperator::BitNot => !a,
+        };
+        match result.ok().and_then(to_literal) {
+            Some(literal) => literal,
+            None => Expr::unary(op, expr),
+        }
+    }
+}
+
+/// Whether evaluating `expr` can't have any effect besides its value.
+fn is_pure(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(_))
+}
+
+fn to_value(literal: &LiteralExpr) -> Value {
+    match literal {
+        LiteralExpr::Int(n) => Value::Int(*n),
+        LiteralExpr::BigInt(n) => Value::BigInt(Rc::new(n.clone())),
+        LiteralExpr::Float(n) => Value::Float(*n),
+        LiteralExpr::True => Value::Bool(true),
+        LiteralExpr::False => Value::Bool(false),
+    }
+}
+
+fn to_literal(value: Value) -> Option<Expr> {
+    match value {
+        Value::Int(n) => Some(Expr::int(n)),
+        Value::BigInt(n) => Some(Expr::big_int(
+            Rc::try_unwrap(n).unwrap_or_else(|n| (*n).clone()),
+        )),
+        Value::Float(n) => Some(Expr::float(n)),
+        Value::Bool(true) => Some(Expr::true_()),
+        Value::Bool(false) => Some(Expr::false_()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::compiler::optimizer::OptLevel;
+    use crate::lexer::lex;
+    use crate::lexer::morph::morph;
+    use crate::parser::parse;
+    use crate::vm::opcode::Opcode;
+
+    fn ast(source: &str) -> Vec<Expr> {
+        parse(&mut morph(lex(source).unwrap())).unwrap()
+    }
+
+    fn folded(source: &str) -> (Vec<Expr>, Vec<CompilerWarning>) {
+        let mut warnings = vec![];
+        let ast = fold(ast(source), &mut warnings);
+        (ast, warnings)
+    }
+
+    #[test]
+    fn literal_operands_fold_to_a_constant() {
+        let (ast, warnings) = folded("puts 1 + 2 * 3");
+        assert_eq!(ast, [Expr::puts(Expr::int(7))]);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn division_by_zero_is_left_to_the_vm() {
+        let (folded, warnings) = folded("puts 1 / 0");
+        assert_eq!(folded, ast("puts 1 / 0"));
+        assert!(matches!(warnings[..], [CompilerWarning::DivisionByZero]));
+
+        // The warning doesn't depend on folding being enabled.
+        let mut warnings = vec![];
+        compile("puts 1 / 0", OptLevel::O0, &mut warnings).unwrap();
+        assert!(matches!(warnings[..], [CompilerWarning::DivisionByZero]));
+    }
+
+    #[test]
+    fn literal_statements_are_dropped() {
+        let (folded, _) = folded("1\n2.5 * 2\nputs 3\ndef f() do\n  4\n  5\nend\n");
+        assert_eq!(folded, ast("puts 3\ndef f() do\n  5\nend\n"));
+    }
+
+    #[test]
+    fn huge_results_are_left_to_the_vm() {
+        let small = compile("puts 2 ** 100", OptLevel::O1, &mut vec![]).unwrap();
+        assert!(!small.chunk().code().contains(&(Opcode::Power as u8)));
+
+        let huge = compile("puts 2 ** 100000000", OptLevel::O1, &mut vec![]).unwrap();
+        assert!(huge.chunk().code().contains(&(Opcode::Power as u8)));
+        let huge = compile("puts 1 << 4000000000", OptLevel::O1, &mut vec![]).unwrap();
+        assert!(huge.chunk().code().contains(&(Opcode::ShiftLeft as u8)));
+    }
+}
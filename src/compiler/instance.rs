@@ -5,6 +5,7 @@ use crate::compiler::object::{Function, FunctionType};
 #[derive(Clone)]
 pub struct CompilerInstance {
     function: Function,
+    #[allow(dead_code)]
     function_type: FunctionType,
     locals: Locals,
     enclosing: Box<Option<CompilerInstance>>,
@@ -43,6 +44,7 @@ impl CompilerInstance {
         &mut self.function
     }
 
+    #[allow(dead_code)]
     pub fn function_type(&self) -> &FunctionType {
         &self.function_type
     }
@@ -55,7 +57,7 @@ impl CompilerInstance {
         &mut self.locals
     }
 
-    pub fn enclosing(&self) -> &Box<Option<CompilerInstance>> {
+    pub fn enclosing(&self) -> &Option<CompilerInstance> {
         &self.enclosing
     }
 
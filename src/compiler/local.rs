@@ -71,7 +71,7 @@ impl Locals {
     }
 
     pub fn insert(&mut self, ident: &str) {
-        if self.get_at_depth(&ident, self.scope_depth).is_some() {
+        if self.get_at_depth(ident, self.scope_depth).is_some() {
             return;
         }
 
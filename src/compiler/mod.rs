@@ -1,25 +1,48 @@
 use crate::compiler::compiler::Compiler;
-use crate::compiler::error::CompileResult;
+use crate::compiler::error::{CompileResult, CompilerWarning};
 use crate::compiler::expr_compiler::compile_expr;
+use crate::compiler::fold::fold;
 use crate::compiler::object::Function;
+use crate::compiler::optimizer::{optimize_function, OptLevel};
 use crate::lexer::lex;
 use crate::lexer::morph::morph;
 use crate::parser::parse;
 
+pub mod bigint;
+pub mod bytecode;
 pub mod chunk;
+#[allow(clippy::module_inception)]
 mod compiler;
-mod error;
+pub mod error;
 mod expr_compiler;
+mod fold;
 mod instance;
 mod local;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
 pub mod object;
+pub mod optimizer;
 pub mod value;
+pub mod verifier;
 
-pub fn compile(source: &str) -> CompileResult<Function> {
-    // TODO: Report errors.
-    let mut tokens = lex(source).unwrap();
+/// Compiles `source` into the script's top-level function. Warnings are
+/// added to `warnings` at every optimization level, even if compiling fails
+/// later on.
+pub fn compile(
+    source: &str,
+    opt_level: OptLevel,
+    warnings: &mut Vec<CompilerWarning>,
+) -> CompileResult<Function> {
+    let mut tokens = lex(source)?;
     tokens = morph(tokens);
-    let ast = parse(&mut tokens).unwrap();
+    let mut ast = parse(&mut tokens)?;
+
+    if opt_level == OptLevel::O0 {
+        // Only for the warnings, the AST is compiled as written.
+        fold(ast.clone(), warnings);
+    } else {
+        ast = fold(ast, warnings);
+    }
 
     let mut compiler = Compiler::new();
 
@@ -27,5 +50,29 @@ pub fn compile(source: &str) -> CompileResult<Function> {
         compile_expr(&mut compiler, expr);
     }
 
-    Ok(compiler.end_compiler())
+    let mut fun = compiler.end_compiler();
+    fun.set_global_names(compiler.take_global_names());
+    if let Some(error) = compiler.take_errors().into_iter().next() {
+        return Err(error);
+    }
+
+    optimize_function(&mut fun, opt_level);
+
+    #[cfg(feature = "print-code")]
+    print_function(&fun);
+
+    Ok(fun)
+}
+
+#[cfg(feature = "print-code")]
+fn print_function(fun: &Function) {
+    use crate::compiler::value::Value;
+
+    println!("{}", fun);
+    println!("{}", fun.chunk());
+    for constant in fun.chunk().constants() {
+        if let Value::Function(nested) = constant {
+            print_function(nested);
+        }
+    }
 }
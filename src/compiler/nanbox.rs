@@ -0,0 +1,251 @@
+use crate::compiler::bigint::BigInt;
+use crate::compiler::object::{Closure, Function, Gc};
+use crate::compiler::value::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter, Result};
+use std::mem;
+use std::rc::Rc;
+
+// Every bit of a quiet NaN plus the sign bit. Packing turns NaNs into the
+// positive quiet NaN, so no float ever has all of these set, leaving three
+// tag bits and a 48-bit payload for everything else.
+const BOXED: u64 = 0xfff8_0000_0000_0000;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_INT: u64 = 0;
+const TAG_BOOL: u64 = 1;
+const TAG_BIG_INT: u64 = 2;
+const TAG_STRING: u64 = 3;
+const TAG_CLOSURE: u64 = 4;
+const TAG_FUNCTION: u64 = 5;
+const TAG_SPILLED: u64 = 6;
+
+thread_local! {
+    static SPILLED: RefCell<Spilled> = RefCell::new(Spilled::default());
+}
+
+/// Values that don't fit in a payload, indexed by the payload instead.
+#[derive(Default)]
+struct Spilled {
+    values: Vec<Option<Value>>,
+    free: Vec<usize>,
+}
+
+impl Spilled {
+    fn insert(&mut self, value: Value) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.values[index] = Some(value);
+                index
+            }
+            None => {
+                self.values.push(Some(value));
+                self.values.len() - 1
+            }
+        }
+    }
+
+    fn get(&self, index: usize) -> &Value {
+        self.values[index]
+            .as_ref()
+            .expect("spilled value already freed")
+    }
+
+    fn remove(&mut self, index: usize) -> Value {
+        self.free.push(index);
+        self.values[index]
+            .take()
+            .expect("spilled value already freed")
+    }
+}
+
+/// A `Value` packed into 8 bytes, for the VM's value stack. Floats are
+/// stored as themselves and everything else hides in the payload of a NaN:
+/// ints of up to 48 bits directly, pointers as their address. Wider ints,
+/// and pointers too wide for a payload, are spilled into a table kept on
+/// the side, so they cost no allocation of their own.
+pub struct PackedValue(u64);
+
+impl PackedValue {
+    fn boxed(tag: u64, payload: u64) -> Self {
+        debug_assert!(payload & !PAYLOAD == 0, "payload too wide to box");
+        PackedValue(BOXED | tag << TAG_SHIFT | payload)
+    }
+
+    /// Packs `value` as its address, which takes over its reference.
+    fn pointer(tag: u64, addr: usize, value: Value) -> Self {
+        if addr as u64 & !PAYLOAD == 0 {
+            mem::forget(value);
+            Self::boxed(tag, addr as u64)
+        } else {
+            Self::spilled(value)
+        }
+    }
+
+    fn spilled(value: Value) -> Self {
+        let index = SPILLED.with(|spilled| spilled.borrow_mut().insert(value));
+        Self::boxed(TAG_SPILLED, index as u64)
+    }
+
+    fn tag(&self) -> Option<u64> {
+        if self.0 & BOXED == BOXED {
+            Some(self.0 >> TAG_SHIFT & 0b111)
+        } else {
+            None
+        }
+    }
+
+    fn payload(&self) -> u64 {
+        self.0 & PAYLOAD
+    }
+
+    /// The value of an int stored inline, without unpacking anything else.
+    pub fn as_int(&self) -> Option<i64> {
+        if self.tag() == Some(TAG_INT) {
+            // Shifting back down sign-extends the payload.
+            Some(((self.0 << 16) as i64) >> 16)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_falsy(&self) -> bool {
+        self.0 == BOXED | TAG_BOOL << TAG_SHIFT
+    }
+
+    /// The GC-managed object this value refers to, if any.
+    pub fn heap_object(&self) -> Option<Gc<dyn Any>> {
+        let addr = self.payload() as usize;
+        unsafe {
+            match self.tag()? {
+                TAG_STRING => Some(Gc::<String>::from_addr(addr).as_any()),
+                TAG_CLOSURE => Some(Gc::<Closure>::from_addr(addr).as_any()),
+                TAG_FUNCTION => Some(Gc::<Function>::from_addr(addr).as_any()),
+                TAG_SPILLED => SPILLED.with(|spilled| spilled.borrow().get(addr).heap_object()),
+                _ => None,
+            }
+        }
+    }
+
+    fn big_int(&self) -> *const BigInt {
+        self.payload() as usize as *const BigInt
+    }
+}
+
+impl From<Value> for PackedValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(n) if n << 16 >> 16 == n => Self::boxed(TAG_INT, n as u64 & PAYLOAD),
+            Value::Int(_) => Self::spilled(value),
+            Value::BigInt(ref n) => Self::pointer(TAG_BIG_INT, Rc::as_ptr(n) as usize, value),
+            Value::Float(n) if n.is_nan() => PackedValue(CANONICAL_NAN),
+            Value::Float(n) => PackedValue(n.to_bits()),
+            Value::Bool(b) => Self::boxed(TAG_BOOL, b as u64),
+            Value::String(s) => Self::pointer(TAG_STRING, s.addr(), value),
+            Value::Closure(closure) => Self::pointer(TAG_CLOSURE, closure.addr(), value),
+            Value::Function(fun) => Self::pointer(TAG_FUNCTION, fun.addr(), value),
+        }
+    }
+}
+
+impl From<PackedValue> for Value {
+    fn from(packed: PackedValue) -> Self {
+        let addr = packed.payload() as usize;
+        let value = unsafe {
+            match packed.tag() {
+                None => Value::Float(f64::from_bits(packed.0)),
+                Some(TAG_INT) => Value::Int(packed.as_int().unwrap()),
+                Some(TAG_BOOL) => Value::Bool(addr != 0),
+                // The packed value's reference moves into the `Rc`.
+                Some(TAG_BIG_INT) => Value::BigInt(Rc::from_raw(packed.big_int())),
+                Some(TAG_STRING) => Value::String(Gc::from_addr(addr)),
+                Some(TAG_CLOSURE) => Value::Closure(Gc::from_addr(addr)),
+                Some(TAG_FUNCTION) => Value::Function(Gc::from_addr(addr)),
+                Some(TAG_SPILLED) => SPILLED.with(|spilled| spilled.borrow_mut().remove(addr)),
+                Some(tag) => unreachable!("invalid value tag {}", tag),
+            }
+        };
+        mem::forget(packed);
+        value
+    }
+}
+
+impl Clone for PackedValue {
+    fn clone(&self) -> Self {
+        match self.tag() {
+            Some(TAG_BIG_INT) => unsafe { Rc::increment_strong_count(self.big_int()) },
+            Some(TAG_SPILLED) => {
+                let value =
+                    SPILLED.with(|spilled| spilled.borrow().get(self.payload() as usize).clone());
+                return Self::spilled(value);
+            }
+            _ => {}
+        }
+        PackedValue(self.0)
+    }
+}
+
+impl Drop for PackedValue {
+    fn drop(&mut self) {
+        match self.tag() {
+            Some(TAG_BIG_INT) => unsafe { Rc::decrement_strong_count(self.big_int()) },
+            Some(TAG_SPILLED) => {
+                // The table may already be gone if the thread is exiting, and
+                // the value is dropped once the table has let go of it.
+                let index = self.payload() as usize;
+                let _ = SPILLED.try_with(|spilled| spilled.borrow_mut().remove(index));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Debug for PackedValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Value::from(self.clone()).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::bigint;
+
+    fn spilled_slots() -> usize {
+        SPILLED.with(|spilled| spilled.borrow().values.len())
+    }
+
+    #[test]
+    fn values_pack_into_half_the_space() {
+        assert_eq!(mem::size_of::<Value>(), 16);
+        assert_eq!(mem::size_of::<PackedValue>(), 8);
+    }
+
+    #[test]
+    fn wide_ints_are_spilled_without_allocating() {
+        let live = bigint::live_bytes();
+        for _ in 0..100 {
+            for n in [i64::MAX, i64::MIN, 1 << 47, -(1 << 47) - 1] {
+                let packed = PackedValue::from(Value::Int(n));
+                assert_eq!(bigint::live_bytes(), live);
+                assert!(matches!(Value::from(packed.clone()), Value::Int(m) if m == n));
+            }
+        }
+        // Dropped values give their slots back for the next ones.
+        assert_eq!(spilled_slots(), 2);
+    }
+
+    #[test]
+    fn pointers_too_wide_for_a_payload_are_spilled() {
+        let n = Rc::new(&BigInt::from(i64::MAX) * &BigInt::from(i64::MAX));
+        let packed = PackedValue::pointer(TAG_BIG_INT, 1 << 50, Value::BigInt(n.clone()));
+        assert_eq!(packed.tag(), Some(TAG_SPILLED));
+        assert_eq!(Rc::strong_count(&n), 2);
+
+        assert!(matches!(Value::from(packed), Value::BigInt(m) if Rc::ptr_eq(&m, &n)));
+        assert_eq!(Rc::strong_count(&n), 1);
+    }
+}
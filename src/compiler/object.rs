@@ -1,9 +1,20 @@
+use crate::compiler::bytecode::{self, Reader};
 use crate::compiler::chunk::Chunk;
+use crate::compiler::error::BytecodeResult;
+#[cfg(feature = "jit")]
+use crate::vm::jit::{self, NativeCode};
+#[cfg(feature = "register-vm")]
+use crate::vm::register::{self, RegisterChunk};
 use std::any;
 use std::any::Any;
 use std::cell::Cell;
+#[cfg(any(feature = "register-vm", feature = "jit"))]
+use std::cell::OnceCell;
 use std::fmt::{Display, Formatter, Pointer, Result};
+use std::mem;
 use std::ops::{Deref, DerefMut};
+#[cfg(feature = "jit")]
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct Closure {
@@ -27,6 +38,18 @@ pub struct Function {
     name: String,
     chunk: Chunk,
     arity: u8,
+    /// For a script, the names of the global slots its code refers to.
+    global_names: Vec<String>,
+    /// The chunk translated for the register VM on first call, or `None` if
+    /// it has to run on the stack VM.
+    #[cfg(feature = "register-vm")]
+    register_chunk: OnceCell<Option<RegisterChunk>>,
+    /// Calls so far, until the function is hot enough to compile.
+    #[cfg(feature = "jit")]
+    calls: Cell<u32>,
+    /// The function's machine code, or `None` if the JIT can't compile it.
+    #[cfg(feature = "jit")]
+    native_code: OnceCell<Option<Rc<NativeCode>>>,
 }
 
 impl Function {
@@ -35,6 +58,13 @@ impl Function {
             name: String::new(),
             chunk: Chunk::new(),
             arity: 0,
+            global_names: vec![],
+            #[cfg(feature = "register-vm")]
+            register_chunk: OnceCell::new(),
+            #[cfg(feature = "jit")]
+            calls: Cell::new(0),
+            #[cfg(feature = "jit")]
+            native_code: OnceCell::new(),
         }
     }
 
@@ -42,10 +72,33 @@ impl Function {
         self.name = name;
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn set_arity(&mut self, arity: u8) {
         self.arity = arity;
     }
 
+    pub fn set_global_names(&mut self, names: Vec<String>) {
+        self.global_names = names;
+    }
+
+    pub fn global_names(&self) -> &[String] {
+        &self.global_names
+    }
+
+    /// Bytes the function holds outside of itself, mostly its chunk.
+    pub fn heap_size(&self) -> usize {
+        self.name.capacity()
+            + self.chunk.heap_size()
+            + self
+                .global_names
+                .iter()
+                .map(|name| mem::size_of::<String>() + name.capacity())
+                .sum::<usize>()
+    }
+
     pub fn chunk(&self) -> &Chunk {
         &self.chunk
     }
@@ -58,9 +111,72 @@ impl Function {
         &mut self.chunk
     }
 
+    #[cfg(feature = "register-vm")]
+    pub fn register_chunk(&self) -> Option<&RegisterChunk> {
+        self.register_chunk
+            .get_or_init(|| register::translate(self))
+            .as_ref()
+    }
+
+    /// Counts a call, and returns the function's machine code once it's been
+    /// called `HOT_CALLS` times and compiled.
+    #[cfg(feature = "jit")]
+    pub fn native_code(&self) -> Option<Rc<NativeCode>> {
+        if let Some(native_code) = self.native_code.get() {
+            return native_code.clone();
+        }
+
+        self.calls.set(self.calls.get() + 1);
+        if self.calls.get() < jit::HOT_CALLS {
+            return None;
+        }
+        self.native_code
+            .get_or_init(|| jit::compile(self).map(Rc::new))
+            .clone()
+    }
+
+    /// Whether the function has been compiled to machine code, without
+    /// counting a call.
+    #[cfg(all(test, feature = "jit"))]
+    pub fn is_compiled(&self) -> bool {
+        matches!(self.native_code.get(), Some(Some(_)))
+    }
+
+    #[allow(dead_code)]
     pub fn arity_mut(&mut self) -> &mut u8 {
         &mut self.arity
     }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        bytecode::write_bytes(out, self.name.as_bytes());
+        out.push(self.arity);
+        self.chunk.serialize(out);
+
+        bytecode::write_u32(out, self.global_names.len() as u32);
+        for name in &self.global_names {
+            bytecode::write_bytes(out, name.as_bytes());
+        }
+    }
+
+    pub fn deserialize(reader: &mut Reader) -> BytecodeResult<Function> {
+        reader.enter_function()?;
+        let fun = Function {
+            name: reader.string()?,
+            arity: reader.u8()?,
+            chunk: Chunk::deserialize(reader)?,
+            global_names: (0..reader.u32()?)
+                .map(|_| reader.string())
+                .collect::<BytecodeResult<_>>()?,
+            #[cfg(feature = "register-vm")]
+            register_chunk: OnceCell::new(),
+            #[cfg(feature = "jit")]
+            calls: Cell::new(0),
+            #[cfg(feature = "jit")]
+            native_code: OnceCell::new(),
+        };
+        reader.leave_function();
+        Ok(fun)
+    }
 }
 
 impl Display for Function {
@@ -90,9 +206,24 @@ impl<T> Gc<T> {
             value,
         })))
     }
+
+    /// Rebuilds a reference from what `addr` returned.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must have come from a `Gc<T>` of this same `T`.
+    #[cfg(feature = "nan-boxing")]
+    pub unsafe fn from_addr(addr: usize) -> Self {
+        Self(addr as *mut ObjBox<T>)
+    }
 }
 
 impl<T: ?Sized> Gc<T> {
+    /// The address of the object, identifying it regardless of its type.
+    pub fn addr(&self) -> usize {
+        self.0 as *const () as usize
+    }
+
     fn report_null(&self) -> ! {
         panic!(
             "Holding null reference to type {} at address {:p}.",
@@ -118,19 +249,13 @@ impl<T: ?Sized> Gc<T> {
     }
 
     pub fn mark(&self) {
-        #[cfg(feature = "trace-gc")]
-        log::debug!("{:p} mark", self.0);
-
         self.deref_non_null().mark.set(true);
     }
 
     pub fn free(self) {
-        #[cfg(feature = "trace-gc")]
-        log::debug!("{:p} free", self.0);
-
         unsafe {
             // drop inner wrapper, and thus the value it owns
-            Box::from_raw(self.0);
+            drop(Box::from_raw(self.0));
         }
     }
 }
@@ -143,7 +268,7 @@ impl<T: Any> Gc<T> {
 
 impl<T: ?Sized> Clone for Gc<T> {
     fn clone(&self) -> Self {
-        Self(self.0)
+        *self
     }
 }
 
@@ -169,6 +294,7 @@ impl<T: ?Sized> DerefMut for Gc<T> {
 
 impl<T: ?Sized> Pointer for Gc<T> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        self.0.fmt(f)
+        // Print only the address, even for trait objects.
+        (self.0 as *const ()).fmt(f)
     }
 }
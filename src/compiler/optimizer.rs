@@ -0,0 +1,330 @@
+use crate::compiler::chunk::Chunk;
+use crate::compiler::fold::MAX_FOLDED_SIZE;
+use crate::compiler::object::{Function, Gc};
+use crate::compiler::value::Value;
+use crate::vm::opcode::Opcode;
+
+/// How hard the compiler tries to improve the bytecode it emits.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OptLevel {
+    /// Emit code exactly as compiled.
+    O0,
+    /// Run the peephole optimizer over every chunk.
+    #[default]
+    O1,
+}
+
+/// Optimizes `fun` and every function nested in its constants.
+pub fn optimize_function(fun: &mut Function, level: OptLevel) {
+    if level == OptLevel::O0 {
+        return;
+    }
+
+    optimize(fun.chunk_mut());
+
+    let nested: Vec<Gc<Function>> = fun
+        .chunk()
+        .constants()
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Function(nested) => Some(*nested),
+            _ => None,
+        })
+        .collect();
+    for mut nested in nested {
+        optimize_function(&mut nested, level);
+    }
+}
+
+/// Rewrites short instruction sequences into cheaper equivalents: constant
+/// arithmetic is folded, values pushed only to be popped are dropped, dead
+/// stores are removed and common pairs are fused into superinstructions.
+pub fn optimize(chunk: &mut Chunk) {
+    // Code that doesn't decode is left alone, for the verifier to reject.
+    let mut code = match decode(chunk) {
+        Some(code) => code,
+        None => return,
+    };
+
+    // Fusing hides the pairs the other rewrites look for, so it runs last.
+    rewrite(chunk, &mut code, simplify);
+    rewrite(chunk, &mut code, fuse);
+
+    *chunk.code_mut() = encode(&code);
+}
+
+/// A decoded instruction. Jumps hold the index of the instruction they
+/// target rather than a byte offset, so code can be rewritten freely.
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    opcode: Opcode,
+    operand: usize,
+}
+
+impl Instruction {
+    fn new(opcode: Opcode, operand: usize) -> Self {
+        Instruction { opcode, operand }
+    }
+
+    fn is_jump(&self) -> bool {
+        matches!(self.opcode, Opcode::Jump | Opcode::JumpIfFalse)
+    }
+
+    fn constant_index(&self) -> Option<usize> {
+        match self.opcode {
+            Opcode::Constant | Opcode::ConstantLong => Some(self.operand),
+            _ => None,
+        }
+    }
+}
+
+/// Matches a pattern at the start of `code`, returning how many
+/// instructions it covers and what to replace them with.
+type Rule = fn(&mut Chunk, &[Instruction]) -> Option<(usize, Vec<Instruction>)>;
+
+/// Applies `rule` wherever it matches, until nothing more does. A pattern
+/// may start at a jump target, but never spans one.
+fn rewrite(chunk: &mut Chunk, code: &mut Vec<Instruction>, rule: Rule) {
+    let mut i = 0;
+    while i < code.len() {
+        let (len, replacement) = match rule(chunk, &code[i..]) {
+            Some(rewrite) if !(i + 1..i + rewrite.0).any(|j| is_jump_target(code, j)) => rewrite,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let added = replacement.len();
+        code.splice(i..i + len, replacement);
+        for instruction in code.iter_mut().filter(|instruction| instruction.is_jump()) {
+            if instruction.operand >= i + len {
+                instruction.operand = instruction.operand - len + added;
+            }
+        }
+
+        // The replacement may complete a pattern that started earlier, like
+        // folding `1 + 2 + 3` from the left.
+        i = i.saturating_sub(2);
+    }
+}
+
+fn is_jump_target(code: &[Instruction], index: usize) -> bool {
+    code.iter()
+        .any(|instruction| instruction.is_jump() && instruction.operand == index)
+}
+
+fn simplify(chunk: &mut Chunk, code: &[Instruction]) -> Option<(usize, Vec<Instruction>)> {
+    use Opcode::*;
+
+    match code {
+        // Constant arithmetic. Operations that fail, like dividing by zero,
+        // are left for the VM to report, and huge results for it to compute.
+        [a, b, op, ..] if a.constant_index().is_some() && b.constant_index().is_some() => {
+            let a = chunk.read_constant(a.constant_index()?).clone();
+            let b = chunk.read_constant(b.constant_index()?).clone();
+            let size = match op.opcode {
+                Multiply => a.product_size(&b),
+                Power => a.power_size(&b),
+                ShiftLeft => a.shift_size(&b),
+                _ => 0,
+            };
+            if size > MAX_FOLDED_SIZE {
+                return None;
+            }
+
+            let value = match op.opcode {
+                Add => a + b,
+                Subtract => a - b,
+                Multiply => a * b,
+                Divide => a / b,
+                Modulo => a % b,
+                Power => a.pow(b),
+                BitAnd => a & b,
+                BitOr => a | b,
+                BitXor => a ^ b,
+                ShiftLeft => a << b,
+                ShiftRight => a >> b,
+                _ => return None,
+            };
+            Some((3, vec![constant(chunk, value.ok()?)]))
+        }
+        [a, op, ..] if a.constant_index().is_some() => {
+            let a = chunk.read_constant(a.constant_index()?).clone();
+            let value = match op.opcode {
+                Negate => -a,
+                BitNot => !a,
+                Pop => return Some((2, vec![])),
+                _ => return None,
+            };
+            Some((2, vec![constant(chunk, value.ok()?)]))
+        }
+        // Pushes without side effects, immediately popped again.
+        [push, pop, ..]
+            if matches!(push.opcode, GetLocal | GetLocalLong | Closure | ClosureLong)
+                && pop.opcode == Pop =>
+        {
+            Some((2, vec![]))
+        }
+        // Locals die with their frame, so a store right before returning is
+        // dead. `SetLocal` leaves the value on the stack for `Return`.
+        [store, ret, ..]
+            if matches!(store.opcode, SetLocal | SetLocalLong) && ret.opcode == Return =>
+        {
+            Some((2, vec![*ret]))
+        }
+        // Storing a local back into itself.
+        [get, set, ..]
+            if matches!(get.opcode, GetLocal | GetLocalLong)
+                && matches!(set.opcode, SetLocal | SetLocalLong)
+                && get.operand == set.operand =>
+        {
+            Some((2, vec![*get]))
+        }
+        _ => None,
+    }
+}
+
+fn fuse(_chunk: &mut Chunk, code: &[Instruction]) -> Option<(usize, Vec<Instruction>)> {
+    use Opcode::*;
+
+    match code {
+        [set, pop, ..] if set.opcode == SetLocal && pop.opcode == Pop => {
+            Some((2, vec![Instruction::new(SetLocalPop, set.operand)]))
+        }
+        [constant, add, ..] if constant.opcode == Constant && add.opcode == Add => {
+            Some((2, vec![Instruction::new(AddConstant, constant.operand)]))
+        }
+        _ => None,
+    }
+}
+
+fn constant(chunk: &mut Chunk, value: Value) -> Instruction {
+    let index = chunk.add_constant(value);
+    if index <= u8::MAX as usize {
+        Instruction::new(Opcode::Constant, index)
+    } else {
+        Instruction::new(Opcode::ConstantLong, index)
+    }
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let bytes = chunk.code();
+    let mut code = vec![];
+    let mut offsets = vec![];
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let opcode = Opcode::from_byte(bytes[offset])?;
+        let next = offset + 1 + opcode.operand_len();
+        let operand = bytes
+            .get(offset + 1..next)?
+            .iter()
+            .fold(0, |operand, &byte| operand << 8 | byte as usize);
+
+        // Jumps temporarily hold their target's byte offset.
+        let operand = if matches!(opcode, Opcode::Jump | Opcode::JumpIfFalse) {
+            next + operand
+        } else {
+            operand
+        };
+        code.push(Instruction::new(opcode, operand));
+        offsets.push(offset);
+        offset = next;
+    }
+    offsets.push(bytes.len());
+
+    for instruction in code.iter_mut().filter(|instruction| instruction.is_jump()) {
+        instruction.operand = offsets.binary_search(&instruction.operand).ok()?;
+    }
+    Some(code)
+}
+
+fn encode(code: &[Instruction]) -> Vec<u8> {
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut offset = 0;
+    for instruction in code {
+        offsets.push(offset);
+        offset += 1 + instruction.opcode.operand_len();
+    }
+    offsets.push(offset);
+
+    let mut bytes = Vec::with_capacity(offset);
+    for (i, instruction) in code.iter().enumerate() {
+        let len = instruction.opcode.operand_len();
+        let operand = if instruction.is_jump() {
+            offsets[instruction.operand] - offsets[i + 1]
+        } else {
+            instruction.operand
+        };
+
+        bytes.push(instruction.opcode as u8);
+        bytes.extend_from_slice(&(operand as u32).to_be_bytes()[4 - len..]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::testing::{output, run_at, vm};
+    use std::fs;
+    use std::path::Path;
+
+    /// A chunk computing `a op b` from two constants.
+    fn constant_operation(a: i64, op: Opcode, b: i64) -> Chunk {
+        let mut chunk = Chunk::new();
+        for n in [a, b] {
+            let index = chunk.add_constant(Value::Int(n));
+            chunk.write(Opcode::Constant);
+            chunk.write_byte(index as u8);
+        }
+        chunk.write(op);
+        chunk.write(Opcode::Return);
+        chunk
+    }
+
+    #[test]
+    fn huge_constant_results_are_left_to_the_vm() {
+        for (a, op, b) in [
+            (2, Opcode::Power, 100_000_000),
+            (1, Opcode::ShiftLeft, 4_000_000_000),
+        ] {
+            let mut small = constant_operation(a, op, 100);
+            optimize(&mut small);
+            assert!(!small.code().contains(&(op as u8)));
+
+            let mut huge = constant_operation(a, op, b);
+            optimize(&mut huge);
+            assert!(huge.code().contains(&(op as u8)));
+        }
+    }
+
+    /// Runs `source` at `opt_level`, returning what it printed and how it
+    /// failed, if it did.
+    fn outcome(source: &str, opt_level: OptLevel) -> (String, Option<String>) {
+        let mut vm = vm();
+        let error = run_at(&mut vm, source, opt_level).err();
+        (output(&mut vm), error.map(|error| error.to_string()))
+    }
+
+    #[test]
+    fn optimized_scripts_behave_the_same() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+        let scripts: Vec<_> = fs::read_dir(dir)
+            .expect("can't read tests/scripts")
+            .map(|entry| entry.expect("can't read tests/scripts").path())
+            .collect();
+        assert!(!scripts.is_empty());
+
+        for path in scripts {
+            let source = fs::read_to_string(&path).unwrap();
+            assert_eq!(
+                outcome(&source, OptLevel::O0),
+                outcome(&source, OptLevel::O1),
+                "{}",
+                path.display()
+            );
+        }
+    }
+}
@@ -1,12 +1,29 @@
+use crate::compiler::bigint::BigInt;
 use crate::compiler::object::{Closure, Function, Gc};
+use crate::vm::error::{RunResult, RuntimeError};
+use std::any::Any;
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter, Result};
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub};
+use std::rc::Rc;
+
+/// The text of a `Value::String`. With `nan-boxing` strings live on the GC
+/// heap, so copying a value never copies its text.
+#[cfg(not(feature = "nan-boxing"))]
+pub type Str = String;
+#[cfg(feature = "nan-boxing")]
+pub type Str = Gc<String>;
+
+/// Estimates how many bytes of digits an operator's result will need.
+pub type SizeFn = fn(&Value, &Value) -> usize;
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Number(f64),
+    Int(i64),
+    BigInt(Rc<BigInt>),
+    Float(f64),
     Bool(bool),
-    String(String),
+    String(Str),
     Closure(Gc<Closure>),
     Function(Gc<Function>),
 }
@@ -14,56 +31,406 @@ pub enum Value {
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::BigInt(n) => write!(f, "{}", n),
+            // Debug keeps the trailing ".0" so floats never print like ints.
+            Value::Float(n) => write!(f, "{:?}", n),
             Value::Bool(b) => write!(f, "{}", b),
-            Value::String(s) => write!(f, "{}", s),
+            Value::String(s) => write!(f, "{}", s.as_str()),
             Value::Closure(clos) => write!(f, "Closure({:?})", clos),
             Value::Function(fun) => write!(f, "Function({})", **fun),
         }
     }
 }
 
-// TODO: Return errors, not panic.
+impl Value {
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn string(s: String) -> Value {
+        Value::String(s)
+    }
+
+    /// Allocates `s` outside the heap. It's tracked once the function
+    /// holding it is adopted by the VM.
+    #[cfg(feature = "nan-boxing")]
+    pub fn string(s: String) -> Value {
+        Value::String(Gc::new(s))
+    }
+
+    // With `nan-boxing` the VM asks the packed value instead.
+    #[cfg_attr(feature = "nan-boxing", allow(dead_code))]
+    pub fn is_falsy(&self) -> bool {
+        matches!(self, Value::Bool(false))
+    }
+
+    /// The GC-managed object this value refers to, if any.
+    pub fn heap_object(&self) -> Option<Gc<dyn Any>> {
+        match self {
+            Value::Closure(closure) => Some(closure.as_any()),
+            Value::Function(fun) => Some(fun.as_any()),
+            #[cfg(feature = "nan-boxing")]
+            Value::String(s) => Some(s.as_any()),
+            _ => None,
+        }
+    }
+
+    /// Stores an integer result as `Int` when it fits in 64 bits.
+    pub fn from_bigint(n: BigInt) -> Value {
+        match n.to_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::BigInt(Rc::new(n)),
+        }
+    }
+
+    /// Exponentiation. Integer powers stay exact, while a negative integer
+    /// exponent or any float operand produces a float.
+    pub fn pow(self, other: Value) -> RunResult<Value> {
+        match (&self, &other) {
+            (Value::Int(_), Value::Int(e)) | (Value::BigInt(_), Value::Int(e)) if *e >= 0 => {
+                let e = match u32::try_from(*e) {
+                    Ok(e) => e,
+                    Err(_) => return self.pow_huge(e & 1 == 1),
+                };
+                if let Value::Int(n) = self {
+                    if let Some(n) = n.checked_pow(e) {
+                        return Ok(Value::Int(n));
+                    }
+                }
+                Ok(Value::from_bigint(self.to_bigint().unwrap().pow(e)))
+            }
+            (_, Value::BigInt(e)) if !e.is_negative() && self.to_bigint().is_some() => {
+                self.pow_huge(e.is_odd())
+            }
+            _ => match (self.to_f64(), other.to_f64()) {
+                (Some(x), Some(y)) => Ok(Value::Float(x.powf(y))),
+                _ => Err(RuntimeError::ArgumentTypes),
+            },
+        }
+    }
+
+    /// `self ** e` for an integer exponent too large to multiply out. Only
+    /// bases 0, 1 and -1 have a result that fits anywhere.
+    fn pow_huge(&self, odd: bool) -> RunResult<Value> {
+        match self {
+            Value::Int(n @ 0..=1) => Ok(Value::Int(*n)),
+            Value::Int(-1) => Ok(Value::Int(if odd { -1 } else { 1 })),
+            _ => Err(RuntimeError::Overflow),
+        }
+    }
+
+    /// Bytes of digits `self * other` needs, or 0 if it fits in an `Int`.
+    pub fn product_size(&self, other: &Value) -> usize {
+        match (self.bits(), other.bits()) {
+            (Some(a), Some(b)) => digit_bytes(a + b),
+            _ => 0,
+        }
+    }
+
+    /// Bytes of digits `self ** exponent` needs, or 0 if it fits in an
+    /// `Int`. Exponents too large to multiply out never allocate.
+    pub fn power_size(&self, exponent: &Value) -> usize {
+        match (self.bits(), exponent) {
+            (Some(bits), Value::Int(e @ 0..=0xFFFF_FFFF)) if self.magnitude_above_one() => {
+                digit_bytes(bits.saturating_mul(*e as u64))
+            }
+            _ => 0,
+        }
+    }
+
+    /// Bytes of digits `self << amount` needs, or 0 if it fits in an `Int`.
+    pub fn shift_size(&self, amount: &Value) -> usize {
+        match (self.bits(), amount) {
+            (Some(bits), Value::Int(n @ 1..=0xFFFF_FFFF)) if bits > 0 => {
+                digit_bytes(bits + *n as u64)
+            }
+            _ => 0,
+        }
+    }
+
+    /// How many bits the magnitude of an integer takes.
+    fn bits(&self) -> Option<u64> {
+        match self {
+            Value::Int(n) => Some(64 - n.unsigned_abs().leading_zeros() as u64),
+            Value::BigInt(n) => Some(n.digits().len() as u64 * 32),
+            _ => None,
+        }
+    }
+
+    fn magnitude_above_one(&self) -> bool {
+        !matches!(self, Value::Int(-1..=1))
+    }
+
+    fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(n) => Some(BigInt::from(*n)),
+            Value::BigInt(n) => Some((**n).clone()),
+            _ => None,
+        }
+    }
+
+    fn to_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::BigInt(n) => Some(n.to_f64()),
+            Value::Float(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// The size of a result `bits` wide, or 0 if it fits in an `Int`.
+fn digit_bytes(bits: u64) -> usize {
+    if bits < 64 {
+        0
+    } else {
+        usize::try_from(bits / 8 + 4).unwrap_or(usize::MAX)
+    }
+}
+
+/// Applies an arithmetic operator, trying the `i64` fast path first and
+/// falling back to `BigInt` when it overflows. Any float operand promotes
+/// the whole operation to float.
+fn arithmetic(
+    a: Value,
+    b: Value,
+    int_op: fn(i64, i64) -> Option<i64>,
+    big_op: fn(&BigInt, &BigInt) -> RunResult<BigInt>,
+    float_op: fn(f64, f64) -> f64,
+) -> RunResult<Value> {
+    if let (Value::Int(x), Value::Int(y)) = (&a, &b) {
+        if let Some(n) = int_op(*x, *y) {
+            return Ok(Value::Int(n));
+        }
+    }
+
+    if let (Some(x), Some(y)) = (a.to_bigint(), b.to_bigint()) {
+        return big_op(&x, &y).map(Value::from_bigint);
+    }
+
+    match (&a, &b) {
+        (Value::Float(_), _) | (_, Value::Float(_)) => match (a.to_f64(), b.to_f64()) {
+            (Some(x), Some(y)) => Ok(Value::Float(float_op(x, y))),
+            _ => Err(RuntimeError::ArgumentTypes),
+        },
+        _ => Err(RuntimeError::ArgumentTypes),
+    }
+}
+
+/// Applies a bitwise operator, which is only defined for integers.
+fn bitwise(
+    a: Value,
+    b: Value,
+    symbol: &'static str,
+    int_op: fn(i64, i64) -> i64,
+    digit_op: fn(u32, u32) -> u32,
+) -> RunResult<Value> {
+    if let (Value::Int(x), Value::Int(y)) = (&a, &b) {
+        return Ok(Value::Int(int_op(*x, *y)));
+    }
+
+    match (a.to_bigint(), b.to_bigint()) {
+        (Some(x), Some(y)) => Ok(Value::from_bigint(x.bitwise(&y, digit_op))),
+        _ => Err(RuntimeError::ExpectedIntegers(symbol)),
+    }
+}
+
+/// Shifts `a` by `b` bits, where a negative amount shifts the other way.
+fn shift(a: Value, b: Value, left: bool) -> RunResult<Value> {
+    let symbol = if left { "<<" } else { ">>" };
+    let amount = match b {
+        Value::Int(n) => n,
+        Value::BigInt(_) => return Err(RuntimeError::Overflow),
+        _ => return Err(RuntimeError::ExpectedIntegers(symbol)),
+    };
+    let left = left == (amount >= 0);
+    let amount = u32::try_from(amount.unsigned_abs()).map_err(|_| RuntimeError::Overflow)?;
+
+    match a {
+        Value::Int(n) if !left => Ok(Value::Int(n >> amount.min(63))),
+        Value::Int(n) if amount < 64 && (n << amount) >> amount == n => Ok(Value::Int(n << amount)),
+        Value::Int(_) | Value::BigInt(_) => {
+            let n = a.to_bigint().unwrap();
+            Ok(Value::from_bigint(if left {
+                n.shift_left(amount)
+            } else {
+                n.shift_right(amount)
+            }))
+        }
+        _ => Err(RuntimeError::ExpectedIntegers(symbol)),
+    }
+}
+
+/// Integer division rounding towards negative infinity, like `7 / -2 == -4`.
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let q = a.checked_div(b)?;
+    if (a % b != 0) && ((a < 0) != (b < 0)) {
+        Some(q - 1)
+    } else {
+        Some(q)
+    }
+}
+
+/// Modulo taking the sign of the divisor, so `a == (a / b) * b + a % b`.
+fn floor_mod(a: i64, b: i64) -> Option<i64> {
+    let r = a.checked_rem(b)?;
+    if r != 0 && ((r < 0) != (b < 0)) {
+        Some(r + b)
+    } else {
+        Some(r)
+    }
+}
+
+fn float_mod(a: f64, b: f64) -> f64 {
+    let r = a % b;
+    if r != 0.0 && ((r < 0.0) != (b < 0.0)) {
+        r + b
+    } else {
+        r
+    }
+}
+
 impl Add for Value {
-    type Output = Self;
+    type Output = RunResult<Self>;
 
     fn add(self, other: Self) -> Self::Output {
-        match (self, other) {
-            (Value::Number(b), Value::Number(a)) => Value::Number(b + a),
-            _ => panic!("Operand must be a number."),
-        }
+        arithmetic(
+            self,
+            other,
+            i64::checked_add,
+            |a, b| Ok(a + b),
+            |a, b| a + b,
+        )
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = RunResult<Self>;
 
     fn sub(self, other: Self) -> Self::Output {
-        match (self, other) {
-            (Value::Number(b), Value::Number(a)) => Value::Number(b - a),
-            _ => panic!("Operand must be a number."),
-        }
+        arithmetic(
+            self,
+            other,
+            i64::checked_sub,
+            |a, b| Ok(a - b),
+            |a, b| a - b,
+        )
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = RunResult<Self>;
 
     fn mul(self, other: Self) -> Self::Output {
-        match (self, other) {
-            (Value::Number(b), Value::Number(a)) => Value::Number(b * a),
-            _ => panic!("Operand must be a number."),
-        }
+        arithmetic(
+            self,
+            other,
+            i64::checked_mul,
+            |a, b| Ok(a * b),
+            |a, b| a * b,
+        )
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = RunResult<Self>;
 
     fn div(self, other: Self) -> Self::Output {
-        match (self, other) {
-            (Value::Number(b), Value::Number(a)) => Value::Number(b / a),
-            _ => panic!("Operand must be a number."),
+        arithmetic(
+            self,
+            other,
+            floor_div,
+            |a, b| {
+                a.div_mod_floor(b)
+                    .map(|(q, _)| q)
+                    .ok_or(RuntimeError::DivisionByZero)
+            },
+            |a, b| a / b,
+        )
+    }
+}
+
+impl Rem for Value {
+    type Output = RunResult<Self>;
+
+    fn rem(self, other: Self) -> Self::Output {
+        arithmetic(
+            self,
+            other,
+            floor_mod,
+            |a, b| {
+                a.div_mod_floor(b)
+                    .map(|(_, r)| r)
+                    .ok_or(RuntimeError::DivisionByZero)
+            },
+            float_mod,
+        )
+    }
+}
+
+impl Neg for Value {
+    type Output = RunResult<Self>;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Value::Int(n) => Ok(n
+                .checked_neg()
+                .map(Value::Int)
+                .unwrap_or_else(|| Value::from_bigint(-&BigInt::from(n)))),
+            Value::BigInt(n) => Ok(Value::from_bigint(-&*n)),
+            Value::Float(n) => Ok(Value::Float(-n)),
+            _ => Err(RuntimeError::ArgumentTypes),
+        }
+    }
+}
+
+/// Bitwise complement, `~x == -x - 1`.
+impl Not for Value {
+    type Output = RunResult<Self>;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Value::Int(n) => Ok(Value::Int(!n)),
+            Value::BigInt(n) => Ok(Value::from_bigint(&-&*n - &BigInt::from(1))),
+            _ => Err(RuntimeError::ExpectedIntegers("~")),
         }
     }
 }
+
+impl BitAnd for Value {
+    type Output = RunResult<Self>;
+
+    fn bitand(self, other: Self) -> Self::Output {
+        bitwise(self, other, "&", |a, b| a & b, |a, b| a & b)
+    }
+}
+
+impl BitOr for Value {
+    type Output = RunResult<Self>;
+
+    fn bitor(self, other: Self) -> Self::Output {
+        bitwise(self, other, "|", |a, b| a | b, |a, b| a | b)
+    }
+}
+
+impl BitXor for Value {
+    type Output = RunResult<Self>;
+
+    fn bitxor(self, other: Self) -> Self::Output {
+        bitwise(self, other, "^", |a, b| a ^ b, |a, b| a ^ b)
+    }
+}
+
+impl Shl for Value {
+    type Output = RunResult<Self>;
+
+    fn shl(self, other: Self) -> Self::Output {
+        shift(self, other, true)
+    }
+}
+
+impl Shr for Value {
+    type Output = RunResult<Self>;
+
+    fn shr(self, other: Self) -> Self::Output {
+        shift(self, other, false)
+    }
+}
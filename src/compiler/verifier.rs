@@ -0,0 +1,284 @@
+use crate::compiler::bytecode::MAX_NESTING;
+use crate::compiler::error::{BytecodeError, BytecodeResult, VerifyError};
+use crate::compiler::object::Function;
+use crate::compiler::value::Value;
+use crate::vm::opcode::Opcode;
+
+/// Checks that `fun` and every function nested in its constants can run
+/// without the VM panicking: every opcode is known, operands are in range,
+/// jumps land on instructions and the stack depth at each instruction is the
+/// same along every path reaching it.
+pub fn verify(fun: &Function) -> BytecodeResult<()> {
+    verify_function(fun, fun.global_names().len(), 1)
+}
+
+/// Verifies `fun` and its nested functions, which share the script's
+/// `globals` slots. `fun` is nested `depth` levels deep, which is held to
+/// the same limit as when loading bytecode.
+fn verify_function(fun: &Function, globals: usize, depth: usize) -> BytecodeResult<()> {
+    if depth > MAX_NESTING {
+        return Err(BytecodeError::TooDeeplyNested);
+    }
+    stack_depths(fun, globals)
+        .map_err(|error| BytecodeError::Verify(fun.name().to_string(), error))?;
+
+    for constant in fun.chunk().constants() {
+        if let Value::Function(nested) = constant {
+            verify_function(nested, globals, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Verifies a single function, returning the stack depth before each
+/// instruction, or `None` for bytes that aren't reachable instructions.
+pub fn stack_depths(fun: &Function, globals: usize) -> Result<Vec<Option<usize>>, VerifyError> {
+    let chunk = fun.chunk();
+    let code = chunk.code();
+    let constants = chunk.constants();
+
+    // Decode every instruction up front, so jump targets can be checked
+    // against instruction boundaries.
+    let mut instructions = vec![None; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = Opcode::from_byte(code[offset])
+            .ok_or(VerifyError::InvalidOpcode(offset, code[offset]))?;
+        let operand_start = offset + 1;
+        let next = operand_start + opcode.operand_len();
+        if next > code.len() {
+            return Err(VerifyError::TruncatedInstruction(offset));
+        }
+
+        let operand = code[operand_start..next]
+            .iter()
+            .fold(0, |operand, &byte| operand << 8 | byte as usize);
+        instructions[offset] = Some((opcode, operand, next));
+        offset = next;
+    }
+
+    // The callee sits in slot zero, followed by the arguments.
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, *fun.arity() as usize + 1)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        let (opcode, operand, next) = match instructions.get(offset) {
+            Some(Some(instruction)) => *instruction,
+            Some(None) => return Err(VerifyError::JumpIntoInstruction(offset)),
+            None if offset == code.len() => return Err(VerifyError::FallsOffEnd),
+            None => return Err(VerifyError::JumpOutOfRange(offset)),
+        };
+
+        match depths[offset] {
+            Some(seen) if seen == depth => continue,
+            Some(_) => return Err(VerifyError::InconsistentStack(offset)),
+            None => depths[offset] = Some(depth),
+        }
+
+        let (pops, pushes) = stack_effect(opcode, operand);
+        if depth < pops {
+            return Err(VerifyError::StackUnderflow(offset));
+        }
+        let depth = depth - pops + pushes;
+
+        match opcode {
+            Opcode::Constant | Opcode::ConstantLong | Opcode::AddConstant
+                if operand >= constants.len() =>
+            {
+                return Err(VerifyError::ConstantOutOfRange(offset));
+            }
+            Opcode::GetGlobalSlot | Opcode::SetGlobalSlot if operand >= globals => {
+                return Err(VerifyError::GlobalOutOfRange(offset));
+            }
+            Opcode::Closure | Opcode::ClosureLong
+                if !matches!(constants.get(operand), Some(Value::Function(_))) =>
+            {
+                return Err(VerifyError::ConstantOutOfRange(offset));
+            }
+            // After `GetLocal` the slot has to lie below the value it pushed.
+            Opcode::GetLocal | Opcode::GetLocalLong if operand + 1 >= depth => {
+                return Err(VerifyError::LocalOutOfRange(offset));
+            }
+            Opcode::SetLocal | Opcode::SetLocalLong if operand >= depth => {
+                return Err(VerifyError::LocalOutOfRange(offset));
+            }
+            // `SetLocalPop` stores into what's left once it has popped.
+            Opcode::SetLocalPop if operand >= depth => {
+                return Err(VerifyError::LocalOutOfRange(offset));
+            }
+            _ => {}
+        }
+
+        match opcode {
+            Opcode::Return | Opcode::TailCall => {}
+            Opcode::Jump => pending.push((next + operand, depth)),
+            Opcode::JumpIfFalse => {
+                pending.push((next + operand, depth));
+                pending.push((next, depth));
+            }
+            _ => pending.push((next, depth)),
+        }
+    }
+
+    Ok(depths)
+}
+
+/// How many values an instruction pops, and how many it pushes afterwards.
+pub fn stack_effect(opcode: Opcode, operand: usize) -> (usize, usize) {
+    match opcode {
+        Opcode::Constant
+        | Opcode::ConstantLong
+        | Opcode::GetLocal
+        | Opcode::GetLocalLong
+        | Opcode::GetGlobalSlot
+        | Opcode::Closure
+        | Opcode::ClosureLong => (0, 1),
+        Opcode::Add
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Divide
+        | Opcode::Modulo
+        | Opcode::Power
+        | Opcode::BitAnd
+        | Opcode::BitOr
+        | Opcode::BitXor
+        | Opcode::ShiftLeft
+        | Opcode::ShiftRight => (2, 1),
+        Opcode::Negate | Opcode::BitNot | Opcode::AddConstant => (1, 1),
+        // These only peek at the top of the stack.
+        Opcode::SetLocal | Opcode::SetLocalLong | Opcode::JumpIfFalse => (1, 1),
+        Opcode::SetGlobalSlot | Opcode::SetLocalPop | Opcode::Puts | Opcode::Pop => (1, 0),
+        Opcode::Call => (operand + 1, 1),
+        // The frame is gone afterwards, along with whatever else it held.
+        Opcode::TailCall => (operand + 1, 0),
+        Opcode::Return => (1, 0),
+        Opcode::Jump => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::testing::nested_functions;
+
+    /// A function of `arity` parameters with the given code and a single
+    /// constant, 1.
+    fn function(arity: u8, code: &[u8]) -> Function {
+        let mut fun = Function::new();
+        fun.set_arity(arity);
+        fun.chunk_mut().add_constant(Value::Int(1));
+        fun.chunk_mut().code_mut().extend_from_slice(code);
+        fun
+    }
+
+    fn verify_code(arity: u8, code: &[u8]) -> Result<Vec<Option<usize>>, VerifyError> {
+        stack_depths(&function(arity, code), 0)
+    }
+
+    const CONSTANT: u8 = Opcode::Constant as u8;
+    const SET_LOCAL_POP: u8 = Opcode::SetLocalPop as u8;
+    const GET_LOCAL: u8 = Opcode::GetLocal as u8;
+    const ADD_CONSTANT: u8 = Opcode::AddConstant as u8;
+    const POP: u8 = Opcode::Pop as u8;
+    const ADD: u8 = Opcode::Add as u8;
+    const JUMP: u8 = Opcode::Jump as u8;
+    const JUMP_IF_FALSE: u8 = Opcode::JumpIfFalse as u8;
+    const RETURN: u8 = Opcode::Return as u8;
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, 0xff, RETURN]),
+            Err(VerifyError::InvalidOpcode(2, 0xff))
+        ));
+    }
+
+    #[test]
+    fn operands_must_fit_in_the_chunk() {
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, RETURN, JUMP, 0]),
+            Err(VerifyError::TruncatedInstruction(3))
+        ));
+    }
+
+    #[test]
+    fn jumps_must_land_on_instructions() {
+        verify_code(0, &[JUMP, 0, 2, CONSTANT, 0, CONSTANT, 0, RETURN]).unwrap();
+        assert!(matches!(
+            verify_code(0, &[JUMP, 0, 1, CONSTANT, 0, CONSTANT, 0, RETURN]),
+            Err(VerifyError::JumpIntoInstruction(4))
+        ));
+    }
+
+    #[test]
+    fn jumps_must_stay_in_the_chunk() {
+        assert!(matches!(
+            verify_code(0, &[JUMP, 0, 9, CONSTANT, 0, RETURN]),
+            Err(VerifyError::JumpOutOfRange(12))
+        ));
+    }
+
+    #[test]
+    fn paths_must_agree_on_the_stack_depth() {
+        // Both paths leave one value on the stack.
+        verify_code(
+            0,
+            &[CONSTANT, 0, JUMP_IF_FALSE, 0, 3, POP, CONSTANT, 0, RETURN],
+        )
+        .unwrap();
+        // Only the fall through pushes another.
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, JUMP_IF_FALSE, 0, 2, CONSTANT, 0, RETURN]),
+            Err(VerifyError::InconsistentStack(7))
+        ));
+    }
+
+    #[test]
+    fn instructions_cant_pop_an_empty_stack() {
+        verify_code(1, &[GET_LOCAL, 1, ADD, RETURN]).unwrap();
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, ADD, ADD, RETURN]),
+            Err(VerifyError::StackUnderflow(3))
+        ));
+    }
+
+    #[test]
+    fn code_must_return_before_the_end() {
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, POP]),
+            Err(VerifyError::FallsOffEnd)
+        ));
+    }
+
+    #[test]
+    fn set_local_pop_stores_below_the_value_it_pops() {
+        verify_code(1, &[CONSTANT, 0, SET_LOCAL_POP, 1, GET_LOCAL, 1, RETURN]).unwrap();
+        // Only the callee is below the popped value.
+        assert!(matches!(
+            verify_code(0, &[CONSTANT, 0, SET_LOCAL_POP, 1, CONSTANT, 0, RETURN]),
+            Err(VerifyError::LocalOutOfRange(2))
+        ));
+    }
+
+    #[test]
+    fn add_constant_needs_an_operand_and_a_constant() {
+        verify_code(1, &[GET_LOCAL, 1, ADD_CONSTANT, 0, RETURN]).unwrap();
+        assert!(matches!(
+            verify_code(1, &[GET_LOCAL, 1, ADD_CONSTANT, 1, RETURN]),
+            Err(VerifyError::ConstantOutOfRange(2))
+        ));
+        assert!(matches!(
+            verify_code(0, &[POP, ADD_CONSTANT, 0, RETURN]),
+            Err(VerifyError::StackUnderflow(1))
+        ));
+    }
+
+    #[test]
+    fn verify_limits_nesting() {
+        assert!(matches!(
+            verify(&nested_functions(MAX_NESTING + 1)),
+            Err(BytecodeError::TooDeeplyNested)
+        ));
+        verify(&nested_functions(MAX_NESTING)).unwrap();
+    }
+}
@@ -1,7 +1,11 @@
+use crate::lexer::token::Position;
+
 pub type LexResult<T> = std::result::Result<T, SyntaxError>;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum SyntaxError {
     UnexpectedEOF,
     UnexpectedChar,
+    MalformedNumber(String, Position),
 }
@@ -29,7 +29,7 @@ impl<'a> Lexer<'a> {
         if c.is_alphabetic() {
             return self.identifier(start);
         }
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number(start);
         }
 
@@ -42,14 +42,32 @@ impl<'a> Lexer<'a> {
             '}' => TokenType::RightBrace,
             ',' => TokenType::Comma,
             '.' => TokenType::Dot,
+            '+' if self.match_('=')? => TokenType::PlusEqual,
             '+' => TokenType::Plus,
+            '-' if self.match_('=')? => TokenType::MinusEqual,
             '-' => TokenType::Minus,
+            '*' if self.match_('*')? => TokenType::StarStar,
+            '*' if self.match_('=')? => TokenType::StarEqual,
             '*' => TokenType::Star,
+            '%' => TokenType::Percent,
+            '&' => TokenType::Ampersand,
+            '|' if self.peek() == Some('|') && self.peek_next() == Some('=') => {
+                self.advance()?;
+                self.advance()?;
+                TokenType::PipePipeEqual
+            }
+            '|' => TokenType::Pipe,
+            '^' => TokenType::Caret,
+            '~' => TokenType::Tilde,
+            '<' if self.match_('<')? => TokenType::LessLess,
+            '>' if self.match_('>')? => TokenType::GreaterGreater,
             '/' => {
                 // Ignore comments.
                 if self.check('/')? {
                     self.advance_while(|&ch| ch != '\n')?;
                     return Ok(None);
+                } else if self.match_('=')? {
+                    TokenType::SlashEqual
                 } else {
                     TokenType::Slash
                 }
@@ -71,23 +89,80 @@ impl<'a> Lexer<'a> {
     }
 
     fn number(&mut self, start: usize) -> LexResult<Option<Token<'a>>> {
-        self.advance_while(|c| c.is_digit(10))?;
-
-        // Look for a fractional part
-        if let Some(peek) = self.peek() {
-            if peek == '.' {
-                if let Some(next) = self.peek_next() {
-                    if next.is_digit(10) {
-                        // Consume the '.'.
-                        self.advance()?;
-
-                        self.advance_while(|c| c.is_digit(10))?;
-                    }
+        let mut token_type = TokenType::Integer;
+
+        let radix = match (&self.source[start..start + 1], self.peek()) {
+            ("0", Some('x')) | ("0", Some('X')) => Some(16),
+            ("0", Some('b')) | ("0", Some('B')) => Some(2),
+            ("0", Some('o')) | ("0", Some('O')) => Some(8),
+            _ => None,
+        };
+
+        let mut well_formed = if let Some(radix) = radix {
+            // Consume the radix prefix.
+            self.advance()?;
+            self.digits(radix, false)?
+        } else {
+            let mut well_formed = self.digits(10, true)?;
+
+            // Look for a fractional part
+            if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+                // Consume the '.'.
+                self.advance()?;
+                well_formed &= self.digits(10, false)?;
+                token_type = TokenType::Float;
+            }
+
+            // Look for an exponent
+            if let Some('e') | Some('E') = self.peek() {
+                self.advance()?;
+                if let Some('+') | Some('-') = self.peek() {
+                    self.advance()?;
+                }
+                well_formed &= self.digits(10, false)?;
+                token_type = TokenType::Float;
+            }
+
+            well_formed
+        };
+
+        // Trailing letters or digits outside the radix, e.g. `0b102` or `12ab`.
+        if self.advance_while(|&c| c.is_alphanumeric() || c == '_')? > 0 {
+            well_formed = false;
+        }
+
+        if !well_formed {
+            let source = self.token_contents(start);
+            let pos = Position::new(start, start + source.len(), self.line);
+            return Err(SyntaxError::MalformedNumber(source.to_string(), pos));
+        }
+
+        self.make_token(token_type, start)
+    }
+
+    /// Consumes a run of digits in `radix`, where single underscores may
+    /// separate digits. Returns whether the run was well formed.
+    fn digits(&mut self, radix: u32, seen_digit: bool) -> LexResult<bool> {
+        let mut seen_digit = seen_digit;
+        let mut last_underscore = false;
+        let mut well_formed = true;
+
+        while let Some(c) = self.peek() {
+            if c.is_digit(radix) {
+                seen_digit = true;
+                last_underscore = false;
+            } else if c == '_' {
+                if !seen_digit || last_underscore {
+                    well_formed = false;
                 }
+                last_underscore = true;
+            } else {
+                break;
             }
+            self.advance()?;
         }
 
-        self.make_token(TokenType::Number, start)
+        Ok(well_formed && seen_digit && !last_underscore)
     }
 
     fn eof(&mut self) -> LexResult<Option<Token<'a>>> {
@@ -106,7 +181,7 @@ impl<'a> Lexer<'a> {
             .peek()
             .map(|&(i, _)| i)
             .unwrap_or(self.source.len());
-        &self.source[start..end].trim_end()
+        self.source[start..end].trim_end()
     }
 
     fn skip_whitespace(&mut self) -> LexResult<()> {
@@ -142,6 +217,14 @@ impl<'a> Lexer<'a> {
             .ok_or(SyntaxError::UnexpectedEOF)
     }
 
+    fn match_(&mut self, c: char) -> LexResult<bool> {
+        if self.peek() != Some(c) {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
     fn check(&mut self, c: char) -> LexResult<bool> {
         self.peek()
             .map(|p| p == c)
@@ -149,7 +232,9 @@ impl<'a> Lexer<'a> {
     }
 
     fn peek_next(&mut self) -> Option<char> {
-        self.chars.nth(1).map(|(_, c)| c)
+        let mut chars = self.chars.clone();
+        chars.next();
+        chars.next().map(|(_, c)| c)
     }
 
     fn peek(&mut self) -> Option<char> {
@@ -160,3 +245,58 @@ impl<'a> Lexer<'a> {
         self.peek().is_none()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::error::SyntaxError;
+    use crate::lexer::lex;
+    use crate::lexer::token::{Position, TokenType};
+
+    fn number(source: &str) -> (TokenType, &str) {
+        let tokens = lex(source).unwrap();
+        (tokens[0].token_type().clone(), tokens[0].source())
+    }
+
+    fn malformed(source: &str) -> (String, Position) {
+        match lex(source) {
+            Err(SyntaxError::MalformedNumber(number, position)) => (number, position),
+            result => panic!("{:?} lexed as {:?}", source, result),
+        }
+    }
+
+    #[test]
+    fn radix_prefixes() {
+        assert_eq!(number("0xFF"), (TokenType::Integer, "0xFF"));
+        assert_eq!(number("0b1010"), (TokenType::Integer, "0b1010"));
+        assert_eq!(number("0o755"), (TokenType::Integer, "0o755"));
+    }
+
+    #[test]
+    fn underscores() {
+        assert_eq!(number("1_000_000"), (TokenType::Integer, "1_000_000"));
+        assert_eq!(number("0xFF_FF"), (TokenType::Integer, "0xFF_FF"));
+    }
+
+    #[test]
+    fn exponents() {
+        assert_eq!(number("1e10"), (TokenType::Float, "1e10"));
+        assert_eq!(number("2.5e-3"), (TokenType::Float, "2.5e-3"));
+        assert_eq!(number("2.5"), (TokenType::Float, "2.5"));
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        assert_eq!(
+            malformed("0b102"),
+            ("0b102".to_string(), Position::new(0, 5, 1))
+        );
+        assert_eq!(
+            malformed("x = 1__0"),
+            ("1__0".to_string(), Position::new(4, 8, 1))
+        );
+        assert_eq!(
+            malformed("puts 1\nputs 1e"),
+            ("1e".to_string(), Position::new(12, 14, 2))
+        );
+    }
+}
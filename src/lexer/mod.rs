@@ -2,12 +2,13 @@ use crate::lexer::error::LexResult;
 use crate::lexer::lexer::Lexer;
 use crate::lexer::token::{Token, TokenType};
 
-mod error;
+pub mod error;
+#[allow(clippy::module_inception)]
 mod lexer;
 pub mod morph;
 pub mod token;
 
-pub fn lex(source: &str) -> LexResult<Vec<Token>> {
+pub fn lex(source: &str) -> LexResult<Vec<Token<'_>>> {
     let mut lexer = Lexer::new(source);
 
     let mut tokens = vec![];
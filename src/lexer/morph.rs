@@ -3,8 +3,7 @@ use crate::lexer::token::{Token, TokenType};
 pub fn morph(mut tokens: Vec<Token>) -> Vec<Token> {
     let mut morphed = vec![];
 
-    while !tokens.is_empty() {
-        let token = tokens.pop().unwrap();
+    while let Some(token) = tokens.pop() {
         match token.token_type() {
             TokenType::Line => {
                 if morphed.is_empty() {
@@ -27,6 +27,8 @@ impl<'a> Token<'a> {
     }
 }
 
+// Nothing lexes `!` or the comparison operators yet.
+#[allow(dead_code)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     LeftParen,
@@ -41,6 +43,19 @@ pub enum TokenType {
     Plus,
     Star,
     Slash,
+    Percent,
+    StarStar,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    LessLess,
+    GreaterGreater,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PipePipeEqual,
 
     Bang,
     BangEqual,
@@ -59,13 +74,15 @@ pub enum TokenType {
     End,
     Identifier,
 
-    Number,
+    Integer,
+    Float,
 
     Line,
+    #[allow(clippy::upper_case_acronyms)]
     EOF,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Position {
     start: usize,
     end: usize,
@@ -77,10 +94,12 @@ impl Position {
         Position { start, end, line }
     }
 
+    #[allow(dead_code)]
     pub fn start(&self) -> &usize {
         &self.start
     }
 
+    #[allow(dead_code)]
     pub fn end(&self) -> &usize {
         &self.end
     }
@@ -103,7 +122,7 @@ impl ToKeyword for &str {
             "def" => TokenType::Def,
             "do" => TokenType::Do,
             "end" => TokenType::End,
-            _ => return TokenType::Identifier,
+            _ => TokenType::Identifier,
         }
     }
 }
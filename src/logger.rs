@@ -0,0 +1,34 @@
+use log::{Level, Log, Metadata, Record};
+
+/// Writes log records to stderr. Collection summaries are logged at `info`,
+/// individual allocations, marks and frees at `debug`, which is enabled by
+/// setting `FUSION_LOG=debug`.
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init() {
+    let level = match std::env::var("FUSION_LOG").as_deref() {
+        Ok("debug") => Level::Debug,
+        Ok("trace") => Level::Trace,
+        _ => Level::Info,
+    };
+
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level.to_level_filter());
+    }
+}
@@ -1,36 +1,182 @@
+mod aot;
 mod compiler;
 mod lexer;
+#[cfg(feature = "trace-gc")]
+mod logger;
 mod parser;
 mod vm;
 
-use crate::vm::interpret;
+use crate::compiler::error::CompilerWarning;
+use crate::compiler::optimizer::OptLevel;
+use crate::compiler::{bytecode, compile};
+use crate::vm::{interpret, load, GcOptions, Limits};
+use std::fs;
 use std::io;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+const USAGE: &str = "usage: fusion [-O0 | -O1] [limits] [script.fu | script.fbc]
+       fusion compile [-O0 | -O1] <script.fu> -o <script.fbc>
+       fusion build [-O0 | -O1] <script.fu | script.fbc> [-o <script.c>]
+
+limits:
+  --fuel <n>         stop after executing n instructions
+  --timeout <ms>     stop after running for ms milliseconds
+  --max-frames <n>   overflow the stack n calls deep
+  --max-stack <n>    overflow the stack at n values
+  --max-heap <bytes> run out of memory past this many bytes of heap
+
+garbage collection:
+  --gc-stress        collect before every allocation
+  --gc-pause <us>    let each incremental step run for us microseconds
+  --gc-stats         print what the collector did once the script stops";
 
 fn main() {
-    let source = r#"
-    def foo(x) do
-      puts x
-    end
+    #[cfg(feature = "trace-gc")]
+    logger::init();
+
+    let (args, opt_level, limits, gc) = match parse_flags(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
+
+    let result = match args.as_slice() {
+        [] => {
+            repl(opt_level, &limits, &gc);
+            Ok(())
+        }
+        [command, input, flag, output] if command == "compile" && flag == "-o" => {
+            compile_file(input, output, opt_level)
+        }
+        [command, input] if command == "build" => {
+            let output = Path::new(input).with_extension("c");
+            build_file(input, &output.to_string_lossy(), opt_level)
+        }
+        [command, input, flag, output] if command == "build" && flag == "-o" => {
+            build_file(input, output, opt_level)
+        }
+        [path] if !path.starts_with('-') => run_file(path, opt_level, &limits, &gc),
+        _ => Err(USAGE.to_string()),
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+/// Takes the optimization level, limits and collector options out of
+/// `args`, leaving the command and its operands.
+fn parse_flags(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(Vec<String>, OptLevel, Limits, GcOptions), String> {
+    let mut rest = vec![];
+    let mut opt_level = OptLevel::default();
+    let mut limits = Limits::default();
+    let mut gc = GcOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O0" => opt_level = OptLevel::O0,
+            "-O1" => opt_level = OptLevel::O1,
+            "--fuel" => limits.fuel = Some(flag_value(&arg, args.next())?),
+            "--timeout" => {
+                limits.timeout = Some(Duration::from_millis(flag_value(&arg, args.next())?))
+            }
+            "--max-frames" => limits.max_frames = Some(flag_value(&arg, args.next())?),
+            "--max-stack" => limits.max_stack = Some(flag_value(&arg, args.next())?),
+            "--max-heap" => limits.max_heap = Some(flag_value(&arg, args.next())?),
+            "--gc-stress" => gc.stress = true,
+            "--gc-pause" => {
+                gc.pause_budget = Some(Duration::from_micros(flag_value(&arg, args.next())?))
+            }
+            "--gc-stats" => gc.print_stats = true,
+            _ => rest.push(arg),
+        }
+    }
+    Ok((rest, opt_level, limits, gc))
+}
+
+fn flag_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("{} expects a number\n\n{}", flag, USAGE))
+}
+
+fn run_file(
+    path: &str,
+    opt_level: OptLevel,
+    limits: &Limits,
+    gc: &GcOptions,
+) -> Result<(), String> {
+    let input = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    run(&input, opt_level, limits, gc)
+}
+
+/// Loads and runs `input`, printing any warnings before it starts.
+fn run(input: &[u8], opt_level: OptLevel, limits: &Limits, gc: &GcOptions) -> Result<(), String> {
+    let mut warnings = vec![];
+    let fun = load(input, opt_level, &mut warnings);
+    print_warnings(&warnings);
+
+    let fun = fun.map_err(|error| format!("error: {}", error))?;
+    interpret(fun, limits, gc).map_err(|error| format!("error: {}", error))
+}
 
-    foo(10)
-    "#;
-    interpret(source);
-    // repl();
+fn print_warnings(warnings: &[CompilerWarning]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+fn compile_file(input: &str, output: &str, opt_level: OptLevel) -> Result<(), String> {
+    let source = fs::read_to_string(input).map_err(|error| format!("{}: {}", input, error))?;
+    let mut warnings = vec![];
+    let fun = compile(&source, opt_level, &mut warnings);
+    print_warnings(&warnings);
+
+    let fun = fun.map_err(|error| format!("error: {:?}", error))?;
+    fs::write(output, bytecode::serialize(&fun)).map_err(|error| format!("{}: {}", output, error))
 }
 
-fn repl() {
-    loop {
-        let line = read_line();
-        interpret(&line);
+/// Writes `input` out as a C program, to be built with e.g.
+/// `cc -O2 script.c -lm`.
+fn build_file(input: &str, output: &str, opt_level: OptLevel) -> Result<(), String> {
+    let bytes = fs::read(input).map_err(|error| format!("{}: {}", input, error))?;
+    let fun = if bytecode::is_bytecode(&bytes) {
+        bytecode::deserialize(&bytes).map_err(|error| format!("error: {:?}", error))?
+    } else {
+        let source = String::from_utf8(bytes).map_err(|error| format!("{}: {}", input, error))?;
+        let mut warnings = vec![];
+        let fun = compile(&source, opt_level, &mut warnings);
+        print_warnings(&warnings);
+        fun.map_err(|error| format!("error: {:?}", error))?
+    };
+
+    let c = aot::emit_c(&fun).map_err(|error| format!("error: {:?}", error))?;
+    fs::write(output, c).map_err(|error| format!("{}: {}", output, error))
+}
+
+fn repl(opt_level: OptLevel, limits: &Limits, gc: &GcOptions) {
+    while let Some(line) = read_line() {
+        if let Err(message) = run(line.as_bytes(), opt_level, limits, gc) {
+            eprintln!("{}", message);
+        }
     }
 }
 
-fn read_line() -> String {
+/// Returns `None` once stdin is closed.
+fn read_line() -> Option<String> {
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
+        Ok(0) => None,
         Ok(_) => {
             trim_newline(&mut input);
-            input
+            Some(input)
         }
         Err(error) => {
             panic!("error: {}", error);
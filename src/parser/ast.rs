@@ -1,13 +1,18 @@
+use crate::compiler::bigint::BigInt;
 use crate::lexer::token::TokenType;
 use crate::parser::error::{ParseResult, ParserError};
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum Expr {
     Binary {
         left: Box<Expr>,
         op: BinaryOperator,
         right: Box<Expr>,
     },
+    Unary {
+        op: UnaryOperator,
+        expr: Box<Expr>,
+    },
     Fun {
         name: Identifier,
         decl: FunDecl,
@@ -18,6 +23,7 @@ pub enum Expr {
     },
     VarSet {
         name: Identifier,
+        op: AssignOperator,
         value: Box<Expr>,
     },
     VarGet {
@@ -41,6 +47,13 @@ impl Expr {
         }
     }
 
+    pub fn unary(op: UnaryOperator, expr: Expr) -> Self {
+        Expr::Unary {
+            op,
+            expr: Box::new(expr),
+        }
+    }
+
     pub fn fun(name: Identifier, decl: FunDecl) -> Self {
         Expr::Fun { name, decl }
     }
@@ -52,9 +65,15 @@ impl Expr {
         }
     }
 
+    #[allow(dead_code)]
     pub fn var_set(name: Identifier, value: Expr) -> Self {
+        Expr::compound_set(name, AssignOperator::Assign, value)
+    }
+
+    pub fn compound_set(name: Identifier, op: AssignOperator, value: Expr) -> Self {
         Expr::VarSet {
             name,
+            op,
             value: Box::new(value),
         }
     }
@@ -63,8 +82,16 @@ impl Expr {
         Expr::VarGet { name }
     }
 
-    pub fn number(n: f64) -> Expr {
-        Expr::Literal(LiteralExpr::Number(n))
+    pub fn int(n: i64) -> Expr {
+        Expr::Literal(LiteralExpr::Int(n))
+    }
+
+    pub fn big_int(n: BigInt) -> Expr {
+        Expr::Literal(LiteralExpr::BigInt(n))
+    }
+
+    pub fn float(n: f64) -> Expr {
+        Expr::Literal(LiteralExpr::Float(n))
     }
 
     pub fn true_() -> Expr {
@@ -91,14 +118,16 @@ impl Expr {
 pub type Identifier = String;
 pub type BlockDecl = Vec<Expr>;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum LiteralExpr {
-    Number(f64),
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
     True,
     False,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum BinaryOperator {
     Equal,
     BangEqual,
@@ -110,6 +139,13 @@ pub enum BinaryOperator {
     Add,
     Divide,
     Multiply,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
 }
 
 impl BinaryOperator {
@@ -119,6 +155,13 @@ impl BinaryOperator {
             TokenType::Plus => BinaryOperator::Add,
             TokenType::Star => BinaryOperator::Multiply,
             TokenType::Slash => BinaryOperator::Divide,
+            TokenType::Percent => BinaryOperator::Modulo,
+            TokenType::StarStar => BinaryOperator::Power,
+            TokenType::Ampersand => BinaryOperator::BitAnd,
+            TokenType::Pipe => BinaryOperator::BitOr,
+            TokenType::Caret => BinaryOperator::BitXor,
+            TokenType::LessLess => BinaryOperator::ShiftLeft,
+            TokenType::GreaterGreater => BinaryOperator::ShiftRight,
             TokenType::BangEqual => BinaryOperator::BangEqual,
             TokenType::Equal => BinaryOperator::Equal,
             TokenType::EqualEqual => BinaryOperator::Equal,
@@ -131,7 +174,48 @@ impl BinaryOperator {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
+pub enum UnaryOperator {
+    Negate,
+    BitNot,
+}
+
+impl UnaryOperator {
+    pub fn from_token(token_type: &TokenType) -> ParseResult<UnaryOperator> {
+        Ok(match token_type {
+            TokenType::Minus => UnaryOperator::Negate,
+            TokenType::Tilde => UnaryOperator::BitNot,
+            _ => return Err(ParserError::ExpectedUnaryOperator(token_type.clone())),
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum AssignOperator {
+    Assign,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    // ||=, only assigns when the current value is falsy.
+    Or,
+}
+
+impl AssignOperator {
+    pub fn from_token(token_type: &TokenType) -> Option<AssignOperator> {
+        Some(match token_type {
+            TokenType::Equal => AssignOperator::Assign,
+            TokenType::PlusEqual => AssignOperator::Add,
+            TokenType::MinusEqual => AssignOperator::Subtract,
+            TokenType::StarEqual => AssignOperator::Multiply,
+            TokenType::SlashEqual => AssignOperator::Divide,
+            TokenType::PipePipeEqual => AssignOperator::Or,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct FunDecl {
     args: Vec<Identifier>,
     body: BlockDecl,
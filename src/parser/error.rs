@@ -3,6 +3,8 @@ use crate::lexer::token::TokenType;
 pub type ParseResult<T> = std::result::Result<T, ParserError>;
 
 // TODO: Use Token not TokenType.
+// Only read when the error is printed with `Debug`.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ParserError {
     Expected(TokenType, TokenType, usize),
@@ -10,5 +12,6 @@ pub enum ParserError {
     ExpectedPrimary(TokenType),
     ExpectedUnaryOperator(TokenType),
     ExpectedBinaryOperator(TokenType),
+    InvalidNumber(String, usize),
     UnexpectedEOF,
 }
@@ -1,8 +1,12 @@
-use crate::lexer::token::TokenType;
-use crate::parser::ast::{BinaryOperator, Expr};
+use crate::compiler::bigint::BigInt;
+use crate::lexer::token::{Token, TokenType};
+use crate::parser::ast::{AssignOperator, BinaryOperator, Expr, UnaryOperator};
 use crate::parser::error::{ParseResult, ParserError};
 use crate::parser::parser::Parser;
 
+// Levels for operators the language doesn't have yet keep their place
+// in the order.
+#[allow(dead_code)]
 #[derive(PartialEq, PartialOrd)]
 enum Precedence {
     None,
@@ -14,12 +18,20 @@ enum Precedence {
     // == !=
     Comparison,
     // < <= > >=
+    BitOr,
+    // | ^
+    BitAnd,
+    // &
+    Shift,
+    // << >>
     Term,
     // + -
     Factor,
-    // * /
+    // * / %
     Unary,
-    // ! -
+    // ! - ~
+    Power,
+    // **
     Call,
     // ()
     Primary,
@@ -31,7 +43,11 @@ impl From<&TokenType> for Precedence {
             TokenType::Equal => Precedence::Assign,
             TokenType::BangEqual | TokenType::EqualEqual => Precedence::Equality,
             TokenType::Plus | TokenType::Minus => Precedence::Term,
-            TokenType::Star | TokenType::Slash => Precedence::Factor,
+            TokenType::Pipe | TokenType::Caret => Precedence::BitOr,
+            TokenType::Ampersand => Precedence::BitAnd,
+            TokenType::LessLess | TokenType::GreaterGreater => Precedence::Shift,
+            TokenType::Star | TokenType::Slash | TokenType::Percent => Precedence::Factor,
+            TokenType::StarStar => Precedence::Power,
             TokenType::Bang => Precedence::Unary,
             TokenType::LeftParen => Precedence::Call,
             TokenType::Dot => Precedence::Call,
@@ -58,9 +74,13 @@ fn parse_expr(parser: &mut Parser, precedence: Precedence) -> ParseResult<Expr>
 
 fn parse_prefix(parser: &mut Parser) -> ParseResult<Expr> {
     match parser.peek_type()? {
-        TokenType::Number | TokenType::Identifier | TokenType::True | TokenType::False => {
-            parse_primary(parser)
-        }
+        TokenType::Integer
+        | TokenType::Float
+        | TokenType::Identifier
+        | TokenType::True
+        | TokenType::False => parse_primary(parser),
+        TokenType::Minus | TokenType::Tilde => parse_unary(parser),
+        TokenType::LeftParen => parse_grouping(parser),
         _ => Err(ParserError::Unexpected(parser.peek_type()?.clone())),
     }
 }
@@ -76,7 +96,14 @@ fn parse_infix(parser: &mut Parser, left: Expr) -> ParseResult<Expr> {
         | TokenType::Plus
         | TokenType::Minus
         | TokenType::Star
-        | TokenType::Slash => parse_binary(parser, left),
+        | TokenType::Slash
+        | TokenType::Percent
+        | TokenType::StarStar
+        | TokenType::Ampersand
+        | TokenType::Pipe
+        | TokenType::Caret
+        | TokenType::LessLess
+        | TokenType::GreaterGreater => parse_binary(parser, left),
         TokenType::LeftParen => parse_call(parser, left),
         _ => Err(ParserError::Unexpected(parser.peek_type()?.clone())),
     }
@@ -85,27 +112,84 @@ fn parse_infix(parser: &mut Parser, left: Expr) -> ParseResult<Expr> {
 fn parse_primary(parser: &mut Parser) -> ParseResult<Expr> {
     let token = parser.consume()?;
     match token.token_type() {
-        TokenType::Number => Ok(Expr::number(token.source().parse::<f64>().unwrap())),
+        TokenType::Integer => parse_integer(&token),
+        TokenType::Float => parse_float(&token),
         TokenType::True => Ok(Expr::true_()),
         TokenType::False => Ok(Expr::false_()),
         TokenType::Identifier => {
             let name = token.source().to_string();
 
-            Ok(if parser.match_(TokenType::Equal)? {
-                let expr = parser.expression()?;
-                Expr::var_set(name, expr)
-            } else {
-                Expr::var_get(name)
+            Ok(match AssignOperator::from_token(parser.peek_type()?) {
+                Some(op) => {
+                    parser.consume()?;
+                    let expr = parser.expression()?;
+                    Expr::compound_set(name, op, expr)
+                }
+                None => Expr::var_get(name),
             })
         }
         _ => Err(ParserError::ExpectedPrimary(token.token_type().clone())),
     }
 }
 
+fn parse_integer(token: &Token) -> ParseResult<Expr> {
+    let source = token.source().replace('_', "");
+    let (radix, digits) = match source.get(..2) {
+        Some("0x") | Some("0X") => (16, &source[2..]),
+        Some("0b") | Some("0B") => (2, &source[2..]),
+        Some("0o") | Some("0O") => (8, &source[2..]),
+        _ => (10, &source[..]),
+    };
+
+    if let Ok(n) = i64::from_str_radix(digits, radix) {
+        return Ok(Expr::int(n));
+    }
+
+    // Literals too large for 64 bits become big integers.
+    BigInt::from_str_radix(digits, radix)
+        .map(Expr::big_int)
+        .map_err(|_| invalid_number(token))
+}
+
+fn parse_float(token: &Token) -> ParseResult<Expr> {
+    token
+        .source()
+        .replace('_', "")
+        .parse::<f64>()
+        .map(Expr::float)
+        .map_err(|_| invalid_number(token))
+}
+
+fn invalid_number(token: &Token) -> ParserError {
+    ParserError::InvalidNumber(token.source().to_string(), *token.position().line())
+}
+
+fn parse_grouping(parser: &mut Parser) -> ParseResult<Expr> {
+    parser.expect(TokenType::LeftParen)?;
+    let expr = parser.expression()?;
+    parser.expect(TokenType::RightParen)?;
+
+    Ok(expr)
+}
+
+fn parse_unary(parser: &mut Parser) -> ParseResult<Expr> {
+    let op_token = parser.consume()?;
+    let op = UnaryOperator::from_token(op_token.token_type())?;
+    let expr = parse_expr(parser, Precedence::Unary)?;
+
+    Ok(Expr::unary(op, expr))
+}
+
 fn parse_binary(parser: &mut Parser, left: Expr) -> ParseResult<Expr> {
     let op_token = parser.consume()?;
-    let precedence = Precedence::from(op_token.token_type());
+    let mut precedence = Precedence::from(op_token.token_type());
     let op = BinaryOperator::from_token(op_token.token_type())?;
+
+    // `**` is right-associative and binds its right operand tighter than
+    // unary operators, so `2 ** -1` and `2 ** 3 ** 2` both parse.
+    if op == BinaryOperator::Power {
+        precedence = Precedence::Unary;
+    }
     let right = parse_expr(parser, precedence)?;
 
     Ok(Expr::binary(left, op, right))
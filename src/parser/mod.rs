@@ -4,8 +4,9 @@ use crate::parser::error::ParseResult;
 use crate::parser::parser::Parser;
 
 pub mod ast;
-mod error;
+pub mod error;
 mod expr_parser;
+#[allow(clippy::module_inception)]
 mod parser;
 
 pub fn parse<'a>(tokens: &'a mut Vec<Token<'a>>) -> ParseResult<Vec<Expr>> {
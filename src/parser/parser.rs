@@ -70,7 +70,7 @@ impl<'a> Parser<'a> {
     }
 
     fn block(&mut self) -> ParseResult<BlockDecl> {
-        self.expect(TokenType::Do);
+        self.expect(TokenType::Do)?;
 
         let mut exprs = vec![];
         while !self.match_(TokenType::End)? {
@@ -83,13 +83,13 @@ impl<'a> Parser<'a> {
     pub fn expect(&mut self, expect: TokenType) -> ParseResult<Token<'a>> {
         if self.check(expect.clone())? {
             // TODO: Clone
-            return Ok(self.consume()?);
+            return self.consume();
         }
 
         Err(ParserError::Expected(
-            expect.clone(),                         // TODO: Clone
-            self.peek_type()?.clone(),              // TODO: Clone
-            self.peek()?.position().line().clone(), // TODO: Clone
+            expect.clone(),            // TODO: Clone
+            self.peek_type()?.clone(), // TODO: Clone
+            *self.peek()?.position().line(),
         ))
     }
 
@@ -118,6 +118,6 @@ impl<'a> Parser<'a> {
     }
 
     pub fn is_eof(&self) -> ParseResult<bool> {
-        Ok(self.check(TokenType::EOF)?)
+        self.check(TokenType::EOF)
     }
 }
@@ -1,3 +1,6 @@
+use crate::compiler::error::{BytecodeError, CompilerError};
+use std::fmt::{Display, Formatter, Result};
+
 pub type RunResult<T> = std::result::Result<T, RuntimeError>;
 
 #[derive(Debug)]
@@ -8,5 +11,138 @@ pub enum RuntimeError {
     InvalidCallee,
     IncorrectArity,
     BadStackIndex(usize, usize),
+    InvalidOpcode(u8),
+    EndOfCode,
+    BadConstantIndex(usize),
     UndefinedGlobal(String),
+    DivisionByZero,
+    Overflow,
+    ExpectedIntegers(&'static str),
+    OutOfMemory,
+    Interrupted,
+    FuelExhausted,
+    StackOverflow(Vec<FrameSummary>),
+    Io,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            RuntimeError::ArgumentTypes => write!(f, "operands have the wrong types"),
+            RuntimeError::StackEmpty => write!(f, "the value stack is empty"),
+            RuntimeError::FrameEmpty => write!(f, "no function is running"),
+            RuntimeError::InvalidCallee => write!(f, "only functions can be called"),
+            RuntimeError::IncorrectArity => write!(f, "wrong number of arguments"),
+            RuntimeError::BadStackIndex(index, len) => {
+                write!(f, "stack slot {} is out of range of {}", index, len)
+            }
+            RuntimeError::InvalidOpcode(byte) => write!(f, "invalid opcode {:#04x}", byte),
+            RuntimeError::EndOfCode => write!(f, "ran past the end of the code"),
+            RuntimeError::BadConstantIndex(index) => write!(f, "no constant {}", index),
+            RuntimeError::UndefinedGlobal(name) => write!(f, "undefined variable '{}'", name),
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::Overflow => write!(f, "integer overflow"),
+            RuntimeError::ExpectedIntegers(symbol) => write!(f, "'{}' expects integers", symbol),
+            RuntimeError::OutOfMemory => write!(f, "out of memory"),
+            RuntimeError::Interrupted => write!(f, "interrupted"),
+            RuntimeError::FuelExhausted => write!(f, "out of fuel"),
+            RuntimeError::StackOverflow(trace) => {
+                write!(f, "stack overflow")?;
+                for frame in trace {
+                    write!(f, "\n    in {}", frame)?;
+                }
+                Ok(())
+            }
+            RuntimeError::Io => write!(f, "couldn't write output"),
+        }
+    }
+}
+
+/// Anything that can go wrong between reading a script and running it.
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(CompilerError),
+    Bytecode(BytecodeError),
+    Runtime(RuntimeError),
+}
+
+impl From<CompilerError> for InterpretError {
+    fn from(error: CompilerError) -> Self {
+        InterpretError::Compile(error)
+    }
+}
+
+impl From<BytecodeError> for InterpretError {
+    fn from(error: BytecodeError) -> Self {
+        InterpretError::Bytecode(error)
+    }
+}
+
+impl From<RuntimeError> for InterpretError {
+    fn from(error: RuntimeError) -> Self {
+        InterpretError::Runtime(error)
+    }
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            InterpretError::Compile(error) => write!(f, "{:?}", error),
+            InterpretError::Bytecode(error) => write!(f, "{:?}", error),
+            InterpretError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// A cycle of functions that called each other `calls` times in a row, so a
+/// deep recursion shows up as one line instead of thousands.
+#[derive(Debug)]
+pub struct FrameSummary {
+    pub functions: Vec<String>,
+    pub calls: usize,
+}
+
+impl Display for FrameSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let functions = self.functions.join(" -> ");
+        if self.calls == 1 {
+            return write!(f, "{}", functions);
+        }
+
+        // Group the digits in threes, e.g. "foo called 9,000 times".
+        let digits = self.calls.to_string();
+        let mut calls = String::new();
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                calls.push(',');
+            }
+            calls.push(digit);
+        }
+        write!(f, "{} called {} times", functions, calls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::testing::{run, vm};
+
+    #[test]
+    fn stack_overflow_folds_recursion_in_its_trace() {
+        let source = "def f(n) do\n  1 + g(n)\nend\ndef g(n) do\n  1 + f(n)\nend\nf(0)";
+        let mut mutual = vm();
+        mutual.set_max_frames(2001);
+        let error = run(&mut mutual, source).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "stack overflow\n    in g -> f called 1,000 times\n    in <script>"
+        );
+
+        let mut direct = vm();
+        direct.set_max_stack(10);
+        let error = run(&mut direct, "def f(n) do\n  1 + f(n + 1)\nend\nf(0)").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "stack overflow\n    in f called 3 times\n    in <script>"
+        );
+    }
 }
@@ -20,6 +20,7 @@ impl CallFrame {
         &self.closure
     }
 
+    #[allow(dead_code)]
     pub fn closure_mut(&mut self) -> &mut Gc<Closure> {
         &mut self.closure
     }
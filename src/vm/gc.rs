@@ -1,28 +1,641 @@
-use crate::compiler::object::Gc;
+use crate::compiler::bigint;
+use crate::compiler::object::{Closure, Function, Gc};
+use crate::compiler::value::Value;
+use crate::vm::error::{RunResult, RuntimeError};
 use crate::vm::vm::VM;
 use std::any::Any;
+use std::fmt::{Display, Formatter, Result};
 use std::io::Write;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Heap size that triggers the first collection.
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+
+/// Factor the live heap is multiplied by to get the next threshold.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Default time an incremental step may take before yielding to the mutator.
+const DEFAULT_PAUSE_BUDGET: Duration = Duration::from_micros(500);
+
+/// What a single collection cycle did. Incremental cycles are spread over
+/// many pauses, `pause` is their sum and `max_pause` the longest of them.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionSummary {
+    pub objects_freed: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub pause: Duration,
+    pub max_pause: Duration,
+}
+
+impl Display for CollectionSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "collected {} objects, {} -> {} bytes, paused {:?} (longest {:?})",
+            self.objects_freed, self.bytes_before, self.bytes_after, self.pause, self.max_pause
+        )
+    }
+}
+
+/// Pause times over the lifetime of a heap.
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    pub cycles: usize,
+    pub objects_freed: usize,
+    pub pauses: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    /// Pauses that took longer than the configured budget.
+    pub over_budget: usize,
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{} cycles freed {} objects in {} pauses, {:?} in all (longest {:?}, {} over budget)",
+            self.cycles,
+            self.objects_freed,
+            self.pauses,
+            self.total_pause,
+            self.max_pause,
+            self.over_budget
+        )
+    }
+}
+
+/// Receives every heap event when the `trace-gc` feature is enabled.
+#[cfg(feature = "trace-gc")]
+pub trait GcTracer {
+    fn allocate(&mut self, obj: Gc<dyn Any>, size: usize, type_name: &str);
+    fn mark(&mut self, obj: Gc<dyn Any>);
+    fn free(&mut self, obj: Gc<dyn Any>, size: usize);
+    fn collect(&mut self, summary: &CollectionSummary);
+}
+
+/// The default tracer, forwarding events to the `log` crate.
+#[cfg(feature = "trace-gc")]
+pub struct LogTracer;
+
+#[cfg(feature = "trace-gc")]
+impl GcTracer for LogTracer {
+    fn allocate(&mut self, obj: Gc<dyn Any>, size: usize, type_name: &str) {
+        log::debug!("{:p} allocate {} bytes for {}", obj, size, type_name);
+    }
+
+    fn mark(&mut self, obj: Gc<dyn Any>) {
+        log::debug!("{:p} mark", obj);
+    }
+
+    fn free(&mut self, obj: Gc<dyn Any>, size: usize) {
+        log::debug!("{:p} free {} bytes", obj, size);
+    }
+
+    fn collect(&mut self, summary: &CollectionSummary) {
+        log::info!("gc: {}", summary);
+    }
+}
+
+/// Where the collector is in its cycle.
+///
+/// Marking is tri-colour: white objects are unmarked, gray ones are marked
+/// and on the gray stack, black ones are marked and fully traced. Objects
+/// allocated while marking start gray, so they survive the cycle. Sweeping
+/// walks `objects[..end]`, compacting survivors down to `write`, while
+/// objects allocated meanwhile are pushed past `end` and left alone.
+///
+/// Heap objects are never written to once they're tracked: a closure only
+/// points at its function, and a function's constants are fixed before it's
+/// adopted. So a black object can't gain a white child, and marking needs no
+/// write barrier. Only the roots change under it, which is why they're
+/// scanned again before sweeping. Anything that starts mutating objects
+/// has to add a barrier that grays the parent again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Mark,
+    Sweep {
+        cursor: usize,
+        write: usize,
+        end: usize,
+    },
+}
+
+/// Every object the VM has allocated, plus the bookkeeping deciding when to
+/// collect them.
+pub struct Heap {
+    objects: Vec<Gc<dyn Any>>,
+    gray_stack: Vec<Gc<dyn Any>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    max_heap_size: Option<usize>,
+    stress: bool,
+    phase: Phase,
+    pause_budget: Duration,
+    cycle: CollectionSummary,
+    stats: GcStats,
+    #[cfg(feature = "trace-gc")]
+    tracer: Box<dyn GcTracer>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: vec![],
+            gray_stack: vec![],
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            max_heap_size: None,
+            stress: false,
+            phase: Phase::Idle,
+            pause_budget: DEFAULT_PAUSE_BUDGET,
+            cycle: CollectionSummary::default(),
+            stats: GcStats::default(),
+            #[cfg(feature = "trace-gc")]
+            tracer: Box::new(LogTracer),
+        }
+    }
+
+    /// Collect on every allocation, to shake out objects that aren't rooted.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// Caps the bytes the heap may hold. Allocations that don't fit even
+    /// after a full collection fail with `RuntimeError::OutOfMemory`.
+    pub fn set_max_heap_size(&mut self, max: Option<usize>) {
+        self.max_heap_size = max;
+    }
+
+    /// How long one incremental marking or sweeping step may run. Scanning
+    /// the roots at the start and end of marking isn't bounded by this.
+    pub fn set_pause_budget(&mut self, budget: Duration) {
+        self.pause_budget = budget;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    fn track(&mut self, obj: Gc<dyn Any>, _type_name: &str) {
+        let size = object_size(obj);
+        self.bytes_allocated += size;
+        self.objects.push(obj);
+
+        // Objects born during marking must survive this cycle.
+        if self.phase == Phase::Mark {
+            self.mark_object(obj);
+        }
+
+        #[cfg(feature = "trace-gc")]
+        self.tracer.allocate(obj, size, _type_name);
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        if let Some(obj) = value.heap_object() {
+            self.mark_object(obj);
+        }
+    }
+
+    fn mark_object(&mut self, obj: Gc<dyn Any>) {
+        if obj.is_marked() {
+            return;
+        }
+
+        obj.mark();
+        self.gray_stack.push(obj);
+
+        #[cfg(feature = "trace-gc")]
+        self.tracer.mark(obj);
+    }
+
+    fn blacken_object(&mut self, obj: Gc<dyn Any>) {
+        if let Some(closure) = obj.downcast_ref::<Closure>() {
+            self.mark_object(closure.fun.as_any());
+        } else if let Some(fun) = obj.downcast_ref::<Function>() {
+            // Constants hold the nested functions and any other heap values.
+            for constant in fun.chunk().constants() {
+                self.mark_value(constant);
+            }
+        }
+    }
+
+    fn begin_cycle(&mut self, roots: Vec<Gc<dyn Any>>) {
+        self.cycle = CollectionSummary {
+            bytes_before: self.bytes_allocated,
+            ..CollectionSummary::default()
+        };
+        self.phase = Phase::Mark;
+        for root in roots {
+            self.mark_object(root);
+        }
+    }
+
+    /// Traces gray objects until none are left or `deadline` passes.
+    /// Returns whether the gray stack was drained.
+    fn mark_step(&mut self, deadline: Option<Instant>) -> bool {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return self.gray_stack.is_empty();
+            }
+        }
+        true
+    }
+
+    /// Re-scans the roots, which the mutator changed while marking, and
+    /// traces whatever they reach before sweeping starts.
+    fn finish_marking(&mut self, roots: Vec<Gc<dyn Any>>) {
+        for root in roots {
+            self.mark_object(root);
+        }
+        self.mark_step(None);
+
+        self.phase = Phase::Sweep {
+            cursor: 0,
+            write: 0,
+            end: self.objects.len(),
+        };
+    }
+
+    /// Frees unmarked objects until the sweep is done or `deadline` passes.
+    /// Returns whether the sweep finished.
+    fn sweep_step(&mut self, deadline: Option<Instant>) -> bool {
+        let (mut cursor, mut write, end) = match self.phase {
+            Phase::Sweep { cursor, write, end } => (cursor, write, end),
+            _ => return true,
+        };
+
+        while cursor < end {
+            let obj = self.objects[cursor];
+            cursor += 1;
+
+            if obj.is_marked() {
+                obj.clear_mark();
+                self.objects[write] = obj;
+                write += 1;
+            } else {
+                let size = object_size(obj);
+                #[cfg(feature = "trace-gc")]
+                self.tracer.free(obj, size);
+
+                self.bytes_allocated -= size;
+                self.cycle.objects_freed += 1;
+                obj.free();
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+        }
+
+        if cursor < end {
+            self.phase = Phase::Sweep { cursor, write, end };
+            return false;
+        }
+
+        // Close the gap left by freed objects, keeping anything allocated
+        // during the sweep.
+        self.objects.drain(write..end);
+        self.phase = Phase::Idle;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+        true
+    }
+
+    fn record_pause(&mut self, pause: Duration) {
+        self.stats.pauses += 1;
+        self.stats.total_pause += pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+        if pause > self.pause_budget {
+            self.stats.over_budget += 1;
+        }
+
+        self.cycle.pause += pause;
+        self.cycle.max_pause = self.cycle.max_pause.max(pause);
+    }
+
+    fn end_cycle(&mut self) -> CollectionSummary {
+        self.stats.cycles += 1;
+        self.stats.objects_freed += self.cycle.objects_freed;
+        self.cycle.bytes_after = self.bytes_allocated;
+
+        #[cfg(feature = "trace-gc")]
+        self.tracer.collect(&self.cycle);
+
+        self.cycle.clone()
+    }
+}
+
+/// The bytes `obj` takes, including what it owns, like a function's code.
+/// Objects never change size once allocated, so this is the same when they
+/// are tracked and when they are freed.
+fn object_size(obj: Gc<dyn Any>) -> usize {
+    let extra = if let Some(fun) = obj.downcast_ref::<Function>() {
+        fun.heap_size()
+    } else if let Some(s) = obj.downcast_ref::<String>() {
+        s.capacity()
+    } else {
+        0
+    };
+    mem::size_of_val(&*obj) + extra
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            obj.free();
+        }
+    }
+}
 
 impl<W: Write> VM<W> {
-    pub fn alloc<T: Any>(&mut self, obj: T) -> Gc<T> {
-        // if self.should_collect() {
-        //     self.collect_garbage();
-        // }
-        //
-        // let size = mem::size_of::<T>();
-        // self.total_allocations += size;
-        //
+    pub fn alloc<T: Any>(&mut self, obj: T) -> RunResult<Gc<T>> {
+        if self.heap().stress {
+            self.collect_garbage();
+        } else {
+            self.gc_step();
+        }
+        self.reserve(mem::size_of::<T>())?;
+
         let ptr = Gc::new(obj);
-        // self.objects.push(ptr.as_any());
-        //
-        // #[cfg(feature = "trace-gc")]
-        // log::debug!(
-        //     "{:p} allocate {} bytes for {}",
-        //     ptr,
-        //     size,
-        //     std::any::type_name::<T>()
-        // ); FIXME TODO
-
-        ptr
+        self.heap_mut()
+            .track(ptr.as_any(), std::any::type_name::<T>());
+        Ok(ptr)
+    }
+
+    pub fn set_max_heap_size(&mut self, max: Option<usize>) {
+        self.heap_mut().set_max_heap_size(max);
+    }
+
+    /// Makes sure the digits of an integer result `size` bytes long fit
+    /// under the heap limit before computing it, so `2 ** 100000000` fails
+    /// right away instead of exhausting the machine.
+    pub fn reserve_digits(&mut self, size: usize) -> RunResult<()> {
+        if size == 0 {
+            return Ok(());
+        }
+        self.reserve(size)
+    }
+
+    /// Bytes counted against the heap limit: every heap object plus the
+    /// digits of every big integer alive.
+    fn heap_usage(&self) -> usize {
+        self.heap().bytes_allocated + bigint::live_bytes()
+    }
+
+    /// Makes sure `size` more bytes fit under the heap limit, collecting
+    /// everything unreachable before giving up.
+    fn reserve(&mut self, size: usize) -> RunResult<()> {
+        let limit = match self.heap().max_heap_size {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.heap_usage().saturating_add(size) <= limit {
+            return Ok(());
+        }
+
+        // A cycle in progress keeps everything allocated while it was
+        // marking, so finish it and then run a fresh one.
+        if self.heap().phase != Phase::Idle {
+            self.collect_garbage();
+        }
+        self.collect_garbage();
+
+        if self.heap_usage().saturating_add(size) > limit {
+            return Err(RuntimeError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Hands a compiled function and every function nested in its constants
+    /// over to the collector. Doesn't collect, as `fun` isn't rooted yet.
+    pub fn adopt_function(&mut self, fun: Gc<Function>) {
+        self.heap_mut()
+            .track(fun.as_any(), std::any::type_name::<Function>());
+
+        for constant in fun.chunk().constants() {
+            match constant {
+                Value::Function(nested) => self.adopt_function(*nested),
+                #[cfg(feature = "nan-boxing")]
+                Value::String(s) => self
+                    .heap_mut()
+                    .track(s.as_any(), std::any::type_name::<String>()),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap().stats()
+    }
+
+    /// Does a bounded amount of collection work: starts a cycle once the
+    /// heap has grown past its threshold, then advances it a step at a time.
+    fn gc_step(&mut self) {
+        let phase = self.heap().phase;
+        if phase == Phase::Idle && self.heap().bytes_allocated <= self.heap().next_gc {
+            return;
+        }
+
+        let start = Instant::now();
+        let deadline = start + self.heap().pause_budget;
+        let mut finished = false;
+
+        match phase {
+            Phase::Idle => {
+                let roots = self.roots();
+                self.heap_mut().begin_cycle(roots);
+            }
+            Phase::Mark => {
+                if self.heap_mut().mark_step(Some(deadline)) {
+                    let roots = self.roots();
+                    self.heap_mut().finish_marking(roots);
+                }
+            }
+            Phase::Sweep { .. } => finished = self.heap_mut().sweep_step(Some(deadline)),
+        }
+
+        let heap = self.heap_mut();
+        heap.record_pause(start.elapsed());
+        if finished {
+            heap.end_cycle();
+        }
+    }
+
+    /// Runs a whole collection in one pause, finishing any cycle that is
+    /// already in progress.
+    pub fn collect_garbage(&mut self) -> CollectionSummary {
+        let start = Instant::now();
+
+        if self.heap().phase == Phase::Idle {
+            let roots = self.roots();
+            self.heap_mut().begin_cycle(roots);
+        }
+        if self.heap().phase == Phase::Mark {
+            let roots = self.roots();
+            self.heap_mut().finish_marking(roots);
+        }
+        let heap = self.heap_mut();
+        heap.sweep_step(None);
+
+        heap.record_pause(start.elapsed());
+        heap.end_cycle()
+    }
+
+    /// The value stack, the closures of active frames and the globals. There
+    /// are no upvalues yet, so nothing else can reach the heap.
+    fn roots(&self) -> Vec<Gc<dyn Any>> {
+        let mut roots: Vec<_> = self
+            .stack()
+            .iter()
+            .filter_map(|slot| slot.heap_object())
+            .chain(
+                self.globals()
+                    .iter()
+                    .flatten()
+                    .filter_map(Value::heap_object),
+            )
+            .collect();
+
+        roots.extend(self.frames().iter().map(|frame| frame.closure().as_any()));
+        roots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::testing::{output, run, vm};
+
+    /// Redefines `f` over and over, calling each version through `apply` so
+    /// it's only rooted by the stack while it runs.
+    fn redefinitions(count: usize) -> (String, String) {
+        let mut source = "def apply(g, x) do\n  g(x)\nend\n".to_string();
+        let mut expected = String::new();
+        for i in 0..count {
+            source += &format!("def f(x) do\n  x + {}\nend\nputs apply(f, 1)\n", i);
+            expected += &format!("{}\n", i + 1);
+        }
+        (source, expected)
+    }
+
+    #[test]
+    fn stress_frees_unreachable_closures() {
+        let (source, expected) = redefinitions(100);
+        let mut vm = vm();
+        vm.heap_mut().set_stress(true);
+
+        run(&mut vm, &source).unwrap();
+        assert_eq!(output(&mut vm), expected);
+        // One collection per closure. The last one ran while the two newest
+        // `f`s were still reachable, and every earlier one was freed.
+        assert!(vm.gc_stats().cycles >= 100);
+        assert_eq!(vm.gc_stats().objects_freed, 98);
+    }
+
+    #[test]
+    fn collect_garbage_keeps_globals() {
+        let (source, expected) = redefinitions(10);
+        let mut vm = vm();
+        run(&mut vm, &source).unwrap();
+        assert_eq!(output(&mut vm), expected);
+
+        vm.collect_garbage();
+        assert!(vm.gc_stats().objects_freed >= 9);
+        // `apply` and the last `f` are still reachable from the globals.
+        run(&mut vm, "puts apply(f, 10)").unwrap();
+        assert_eq!(output(&mut vm), "19\n");
+    }
+
+    #[test]
+    fn incremental_cycles_spread_over_pauses() {
+        let (source, expected) = redefinitions(20);
+        let mut vm = vm();
+        run(&mut vm, &source).unwrap();
+        assert_eq!(output(&mut vm), expected);
+
+        // Each step traces or sweeps a single object.
+        vm.heap_mut().set_pause_budget(Duration::ZERO);
+        vm.heap_mut().next_gc = 0;
+        vm.gc_step();
+        assert_eq!(vm.heap().phase, Phase::Mark);
+
+        // Nothing refers to it, but it was born while marking.
+        let orphan = vm.alloc(String::from("orphan")).unwrap().as_any();
+        let mut steps = 1;
+        while vm.heap().phase != Phase::Idle {
+            vm.gc_step();
+            steps += 1;
+        }
+        assert!(steps > 2, "{} steps", steps);
+        assert!(vm
+            .heap()
+            .objects
+            .iter()
+            .any(|obj| obj.addr() == orphan.addr()));
+
+        let stats = vm.gc_stats();
+        assert_eq!(stats.cycles, 1);
+        // The allocation took a step too.
+        assert_eq!(stats.pauses, steps + 1);
+        // No step fits in a zero budget.
+        assert!(stats.over_budget > 0);
+        assert!(stats.max_pause <= stats.total_pause);
+        assert!(stats.objects_freed >= 19);
+
+        // The next cycle frees it, and the script still runs.
+        vm.collect_garbage();
+        assert!(!vm
+            .heap()
+            .objects
+            .iter()
+            .any(|obj| obj.addr() == orphan.addr()));
+        run(&mut vm, "puts apply(f, 10)").unwrap();
+        assert_eq!(output(&mut vm), "29\n");
+    }
+
+    #[test]
+    fn heap_limit_refuses_huge_integers_before_computing_them() {
+        let squarings = "x = x * x\n".repeat(20);
+        let sources = [
+            "n = 4000000000\nputs 1 << n".to_string(),
+            "e = 100000000\nputs 2 ** e".to_string(),
+            format!("x = 2 ** 1000\n{}", squarings),
+        ];
+        for source in &sources {
+            let mut vm = vm();
+            vm.set_max_heap_size(Some(16 * 1024));
+            let result = run(&mut vm, source);
+            assert!(
+                matches!(result, Err(RuntimeError::OutOfMemory)),
+                "{}: {:?}",
+                source,
+                result
+            );
+        }
+
+        let mut vm = vm();
+        vm.set_max_heap_size(Some(16 * 1024));
+        run(&mut vm, "e = 4000\nx = 2 ** e\nputs x % 1000").unwrap();
+        assert_eq!(output(&mut vm), "376\n");
+    }
+
+    #[test]
+    fn functions_count_their_code() {
+        let source = "puts 1\n".repeat(1000);
+        let mut vm = vm();
+        run(&mut vm, &source).unwrap();
+        // Each line compiles to at least two bytes.
+        assert!(vm.heap().bytes_allocated() > 2000);
     }
 }
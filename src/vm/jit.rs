@@ -0,0 +1,547 @@
+use crate::compiler::chunk::Chunk;
+use crate::compiler::object::Function;
+use crate::compiler::value::Value;
+use crate::vm::opcode::Opcode;
+use crate::vm::vm::VM;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::{mem, ptr};
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs a Unix x86-64 target");
+
+/// Calls after which a function is compiled.
+pub const HOT_CALLS: u32 = 1_000;
+
+/// Native code returns this when the interpreter has to take over.
+const DEOPT: u64 = 1;
+
+/// Compiled code takes its locals and its globals, and returns 0 with the
+/// result in local 0, or `DEOPT`.
+type Entry = unsafe extern "sysv64" fn(*mut i64, *mut i64) -> u64;
+
+/// A function compiled to x86-64, one template per instruction. Only
+/// functions of integers are compiled: code that calls, prints or makes
+/// closures stays in the interpreter, and so does a call as soon as it
+/// meets anything that isn't an `i64`, overflows or divides by zero.
+///
+/// Compiled code can neither loop nor call, so it always finishes quickly
+/// and doesn't count fuel. It has no side effects until it returns, either:
+/// globals are stored to a copy, so after a deopt the interpreter simply
+/// runs the call again from the start.
+#[derive(Debug)]
+pub struct NativeCode {
+    memory: ExecutableMemory,
+    arity: usize,
+    /// The global slots the code uses, in the order it numbers them.
+    globals: Vec<usize>,
+    /// Whether the code stores to each of them.
+    stores: Vec<bool>,
+    /// Locals, then each global as a value and whether it's an int. The
+    /// code never calls, so one buffer does for every call.
+    scratch: RefCell<Vec<i64>>,
+}
+
+impl<W: Write> VM<W> {
+    /// Runs the call whose callee sits at `frame_start` with `native`,
+    /// replacing the callee and its arguments with the result. Returns false,
+    /// having changed nothing, if the interpreter has to run it instead.
+    pub fn call_native(&mut self, native: &NativeCode, frame_start: usize) -> bool {
+        let mut scratch = native.scratch.borrow_mut();
+        let (locals, globals) = scratch.split_at_mut(native.arity + 1);
+
+        for (i, local) in locals.iter_mut().enumerate().skip(1) {
+            match self.stack_value(frame_start + i) {
+                Value::Int(n) => *local = n,
+                _ => return false,
+            }
+        }
+
+        for (&slot, global) in native.globals.iter().zip(globals.chunks_mut(2)) {
+            match self.globals()[slot] {
+                Some(Value::Int(n)) => global.copy_from_slice(&[n, 1]),
+                _ => global.copy_from_slice(&[0, 0]),
+            }
+        }
+
+        let entry: Entry = unsafe { mem::transmute(native.memory.ptr) };
+        if unsafe { entry(locals.as_mut_ptr(), globals.as_mut_ptr()) } == DEOPT {
+            return false;
+        }
+
+        for (i, &slot) in native.globals.iter().enumerate() {
+            if native.stores[i] && globals[2 * i + 1] != 0 {
+                self.set_global_slot(slot, Value::Int(globals[2 * i]));
+            }
+        }
+
+        self.stack_mut().truncate(frame_start);
+        self.push(Value::Int(locals[0]));
+        true
+    }
+}
+
+/// Compiles `fun`, or returns `None` if it uses anything the JIT doesn't
+/// support.
+pub fn compile(fun: &Function) -> Option<NativeCode> {
+    let chunk = fun.chunk();
+    let code = chunk.code();
+    let arity = *fun.arity() as usize;
+    let mut globals = vec![];
+    let mut stores = vec![];
+    let mut asm = Assembler::new();
+
+    let mut ip = 0;
+    while ip < code.len() {
+        asm.labels.insert(ip, asm.code.len());
+        let opcode = Opcode::from_byte(code[ip])?;
+        let len = opcode.operand_len();
+        let operand = code
+            .get(ip + 1..ip + 1 + len)?
+            .iter()
+            .fold(0, |operand, &byte| operand << 8 | byte as usize);
+        ip += 1 + len;
+
+        match opcode {
+            Opcode::Constant | Opcode::ConstantLong => asm.push_int(int_constant(chunk, operand)?),
+            Opcode::AddConstant => {
+                asm.push_int(int_constant(chunk, operand)?);
+                asm.arithmetic(&[0x48, 0x01, 0xc8]);
+            }
+            Opcode::GetLocal | Opcode::GetLocalLong if (1..=arity).contains(&operand) => {
+                // push qword [rbx + 8 * slot]
+                asm.bytes(&[0xff, 0xb3]);
+                asm.imm32(8 * operand as i32);
+            }
+            Opcode::SetLocal | Opcode::SetLocalLong if (1..=arity).contains(&operand) => {
+                // mov rax, [rsp]; mov [rbx + 8 * slot], rax
+                asm.bytes(&[0x48, 0x8b, 0x04, 0x24, 0x48, 0x89, 0x83]);
+                asm.imm32(8 * operand as i32);
+            }
+            Opcode::SetLocalPop if (1..=arity).contains(&operand) => {
+                // pop rax; mov [rbx + 8 * slot], rax
+                asm.bytes(&[0x58, 0x48, 0x89, 0x83]);
+                asm.imm32(8 * operand as i32);
+            }
+            Opcode::GetGlobalSlot => {
+                let offset = 16 * global_index(&mut globals, &mut stores, operand) as i32;
+                // cmp qword [r12 + offset + 8], 0; je deopt
+                asm.bytes(&[0x49, 0x83, 0xbc, 0x24]);
+                asm.imm32(offset + 8);
+                asm.bytes(&[0x00]);
+                asm.jump(&[0x0f, 0x84], Target::Deopt);
+                // push qword [r12 + offset]
+                asm.bytes(&[0x41, 0xff, 0xb4, 0x24]);
+                asm.imm32(offset);
+            }
+            Opcode::SetGlobalSlot => {
+                let index = global_index(&mut globals, &mut stores, operand);
+                stores[index] = true;
+                let offset = 16 * index as i32;
+                // pop rax; mov [r12 + offset], rax; mov qword [r12 + offset + 8], 1
+                asm.bytes(&[0x58, 0x49, 0x89, 0x84, 0x24]);
+                asm.imm32(offset);
+                asm.bytes(&[0x49, 0xc7, 0x84, 0x24]);
+                asm.imm32(offset + 8);
+                asm.imm32(1);
+            }
+            Opcode::Add => asm.arithmetic(&[0x48, 0x01, 0xc8]),
+            Opcode::Subtract => asm.arithmetic(&[0x48, 0x29, 0xc8]),
+            Opcode::Multiply => asm.arithmetic(&[0x48, 0x0f, 0xaf, 0xc1]),
+            Opcode::Divide => asm.division(false),
+            Opcode::Modulo => asm.division(true),
+            Opcode::BitAnd => asm.bytes(&[0x59, 0x58, 0x48, 0x21, 0xc8, 0x50]),
+            Opcode::BitOr => asm.bytes(&[0x59, 0x58, 0x48, 0x09, 0xc8, 0x50]),
+            Opcode::BitXor => asm.bytes(&[0x59, 0x58, 0x48, 0x31, 0xc8, 0x50]),
+            Opcode::ShiftLeft => asm.shift(true),
+            Opcode::ShiftRight => asm.shift(false),
+            Opcode::Negate => {
+                // pop rax; neg rax; jo deopt; push rax
+                asm.bytes(&[0x58, 0x48, 0xf7, 0xd8]);
+                asm.jump(&[0x0f, 0x80], Target::Deopt);
+                asm.bytes(&[0x50]);
+            }
+            Opcode::BitNot => asm.bytes(&[0x58, 0x48, 0xf7, 0xd0, 0x50]),
+            Opcode::Pop => asm.bytes(&[0x58]),
+            Opcode::Jump => asm.jump(&[0xe9], Target::Bytecode(ip + operand)),
+            // Only `false` is falsy, and compiled code only has ints.
+            Opcode::JumpIfFalse => {}
+            Opcode::Return => {
+                // pop rax; mov [rbx], rax; xor eax, eax; jmp exit
+                asm.bytes(&[0x58, 0x48, 0x89, 0x03, 0x31, 0xc0]);
+                asm.jump(&[0xe9], Target::Exit);
+            }
+            _ => return None,
+        }
+    }
+
+    let code = asm.finish()?;
+    Some(NativeCode {
+        memory: ExecutableMemory::new(&code)?,
+        arity,
+        scratch: RefCell::new(vec![0; arity + 1 + 2 * globals.len()]),
+        globals,
+        stores,
+    })
+}
+
+fn int_constant(chunk: &Chunk, index: usize) -> Option<i64> {
+    match chunk.constants().get(index)? {
+        Value::Int(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn global_index(globals: &mut Vec<usize>, stores: &mut Vec<bool>, slot: usize) -> usize {
+    if let Some(index) = globals.iter().position(|&global| global == slot) {
+        return index;
+    }
+
+    globals.push(slot);
+    stores.push(false);
+    globals.len() - 1
+}
+
+/// Where a jump goes.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Bytecode(usize),
+    Deopt,
+    Exit,
+}
+
+/// Emits machine code for a stack machine on the native stack. `rbx` points
+/// at the locals, `r12` at the globals, and `rax`, `rcx`, `rdx` and `r8`
+/// are scratch.
+struct Assembler {
+    code: Vec<u8>,
+    /// Where the code of each bytecode instruction starts.
+    labels: HashMap<usize, usize>,
+    /// Jump offsets still to be filled in.
+    jumps: Vec<(usize, Target)>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let mut asm = Assembler {
+            code: vec![],
+            labels: HashMap::new(),
+            jumps: vec![],
+        };
+        // push rbp; mov rbp, rsp; push rbx; push r12; mov rbx, rdi; mov r12, rsi
+        asm.bytes(&[
+            0x55, 0x48, 0x89, 0xe5, 0x53, 0x41, 0x54, 0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4,
+        ]);
+        asm
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: i32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// Emits a jump instruction with a 32-bit offset to `target`.
+    fn jump(&mut self, opcode: &[u8], target: Target) {
+        self.bytes(opcode);
+        self.jumps.push((self.code.len(), target));
+        self.imm32(0);
+    }
+
+    fn push_int(&mut self, n: i64) {
+        match i32::try_from(n) {
+            // push imm32, which is sign-extended
+            Ok(n) => {
+                self.bytes(&[0x68]);
+                self.imm32(n);
+            }
+            // mov rax, imm64; push rax
+            Err(_) => {
+                self.bytes(&[0x48, 0xb8]);
+                self.bytes(&n.to_le_bytes());
+                self.bytes(&[0x50]);
+            }
+        }
+    }
+
+    /// Pops `rcx` and `rax`, applies `op` to `rax` and pushes it, unless it
+    /// overflows.
+    fn arithmetic(&mut self, op: &[u8]) {
+        self.bytes(&[0x59, 0x58]);
+        self.bytes(op);
+        self.jump(&[0x0f, 0x80], Target::Deopt);
+        self.bytes(&[0x50]);
+    }
+
+    /// Floored division or modulo, like `Value`'s. Dividing by zero or -1,
+    /// which can overflow, is left to the interpreter.
+    fn division(&mut self, modulo: bool) {
+        // pop rcx; pop rax; test rcx, rcx; jz deopt; cmp rcx, -1; je deopt
+        self.bytes(&[0x59, 0x58, 0x48, 0x85, 0xc9]);
+        self.jump(&[0x0f, 0x84], Target::Deopt);
+        self.bytes(&[0x48, 0x83, 0xf9, 0xff]);
+        self.jump(&[0x0f, 0x84], Target::Deopt);
+        // cqo; idiv rcx; test rdx, rdx; jz +11; mov r8, rdx; xor r8, rcx; jns +3
+        self.bytes(&[
+            0x48, 0x99, 0x48, 0xf7, 0xf9, 0x48, 0x85, 0xd2, 0x74, 0x0b, 0x49, 0x89, 0xd0, 0x49,
+            0x31, 0xc8, 0x79, 0x03,
+        ]);
+        if modulo {
+            // add rdx, rcx; push rdx
+            self.bytes(&[0x48, 0x01, 0xca, 0x52]);
+        } else {
+            // dec rax; push rax
+            self.bytes(&[0x48, 0xff, 0xc8, 0x50]);
+        }
+    }
+
+    /// Shifts by 0 to 63 bits. Negative or longer shifts, and left shifts
+    /// that lose bits, are left to the interpreter.
+    fn shift(&mut self, left: bool) {
+        // pop rcx; pop rax; cmp rcx, 63; ja deopt
+        self.bytes(&[0x59, 0x58, 0x48, 0x83, 0xf9, 0x3f]);
+        self.jump(&[0x0f, 0x87], Target::Deopt);
+        if left {
+            // mov rdx, rax; shl rax, cl; mov r8, rax; sar r8, cl; cmp r8, rdx; jne deopt
+            self.bytes(&[
+                0x48, 0x89, 0xc2, 0x48, 0xd3, 0xe0, 0x49, 0x89, 0xc0, 0x49, 0xd3, 0xf8, 0x49, 0x39,
+                0xd0,
+            ]);
+            self.jump(&[0x0f, 0x85], Target::Deopt);
+        } else {
+            // sar rax, cl
+            self.bytes(&[0x48, 0xd3, 0xf8]);
+        }
+        self.bytes(&[0x50]);
+    }
+
+    /// Adds the deopt and exit paths, and fills in the jumps. Returns `None`
+    /// if a jump lands mid-instruction.
+    fn finish(mut self) -> Option<Vec<u8>> {
+        let deopt = self.code.len();
+        // mov eax, DEOPT
+        self.bytes(&[0xb8]);
+        self.imm32(DEOPT as i32);
+
+        let exit = self.code.len();
+        // lea rsp, [rbp - 16]; pop r12; pop rbx; pop rbp; ret
+        self.bytes(&[0x48, 0x8d, 0x65, 0xf0, 0x41, 0x5c, 0x5b, 0x5d, 0xc3]);
+
+        for &(at, target) in &self.jumps {
+            let to = match target {
+                Target::Bytecode(ip) => *self.labels.get(&ip)?,
+                Target::Deopt => deopt,
+                Target::Exit => exit,
+            };
+            let offset = to as i32 - (at as i32 + 4);
+            self.code[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+        Some(self.code)
+    }
+}
+
+/// A read-only, executable copy of some machine code.
+#[derive(Debug)]
+struct ExecutableMemory {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl ExecutableMemory {
+    fn new(code: &[u8]) -> Option<Self> {
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                code.len(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+
+            let memory = ExecutableMemory {
+                ptr,
+                len: code.len(),
+            };
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if libc::mprotect(ptr, code.len(), libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(memory)
+        }
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::object::Gc;
+    use crate::vm::error::RuntimeError;
+    use crate::vm::testing::{output, run, vm};
+    use std::sync::atomic::Ordering;
+
+    /// A script defining `f(a, b)` as `body`, then calling it `calls` times.
+    fn script(body: &str, calls: u32) -> String {
+        let mut source = format!("def f(a, b) do\n  {}\nend\n", body);
+        for i in 0..calls {
+            source.push_str(&format!("puts f({}, 3)\n", i));
+        }
+        source
+    }
+
+    fn function(vm: &mut VM<Vec<u8>>, name: &str) -> Gc<Function> {
+        let slot = vm.global_slot(name);
+        match vm.get_global_slot(slot) {
+            Ok(Value::Closure(closure)) => closure.fun,
+            _ => panic!("{} isn't a function", name),
+        }
+    }
+
+    /// Defines `f(a, b)` as `body` and compiles it.
+    fn compiled(vm: &mut VM<Vec<u8>>, body: &str) -> NativeCode {
+        run(vm, &script(body, 0)).unwrap();
+        compile(&function(vm, "f")).expect("f isn't compiled")
+    }
+
+    /// Calls `native` with `a` and `b`, or returns `None` if it deopts.
+    fn call(vm: &mut VM<Vec<u8>>, native: &NativeCode, a: i64, b: i64) -> Option<i64> {
+        let frame_start = vm.stack().len();
+        // Compiled code never looks at its callee.
+        for n in [0, a, b] {
+            vm.push(Value::Int(n));
+        }
+
+        if vm.call_native(native, frame_start) {
+            match vm.pop() {
+                Ok(Value::Int(n)) => return Some(n),
+                result => panic!("compiled code returned {:?}", result),
+            }
+        }
+        assert_eq!(vm.stack().len(), frame_start + 3);
+        vm.stack_mut().truncate(frame_start);
+        None
+    }
+
+    #[test]
+    fn hot_functions_are_compiled() {
+        let mut vm = vm();
+        run(&mut vm, &script("a * b + 1", HOT_CALLS - 1)).unwrap();
+        assert!(!function(&mut vm, "f").is_compiled());
+
+        run(&mut vm, "puts f(1, 2)").unwrap();
+        assert!(function(&mut vm, "f").is_compiled());
+        assert!(output(&mut vm).ends_with("\n2995\n3\n"));
+    }
+
+    #[test]
+    fn functions_the_jit_cant_run_stay_interpreted() {
+        let mut vm = vm();
+        run(&mut vm, &script("puts a", HOT_CALLS)).unwrap();
+        assert!(!function(&mut vm, "f").is_compiled());
+    }
+
+    #[test]
+    fn overflow_deopts() {
+        let mut vm = vm();
+        let add = compiled(&mut vm, "a + b");
+        assert_eq!(call(&mut vm, &add, 1, 2), Some(3));
+        assert_eq!(call(&mut vm, &add, i64::MAX, 1), None);
+
+        let multiply = compiled(&mut vm, "a * b");
+        assert_eq!(call(&mut vm, &multiply, i64::MIN, -1), None);
+
+        let negate = compiled(&mut vm, "-a");
+        assert_eq!(call(&mut vm, &negate, i64::MIN, 0), None);
+    }
+
+    #[test]
+    fn dividing_by_zero_or_minus_one_deopts() {
+        let mut vm = vm();
+        let divide = compiled(&mut vm, "a / b");
+        assert_eq!(call(&mut vm, &divide, -7, 2), Some(-4));
+        assert_eq!(call(&mut vm, &divide, 7, 0), None);
+        assert_eq!(call(&mut vm, &divide, 7, -1), None);
+
+        let modulo = compiled(&mut vm, "a % b");
+        assert_eq!(call(&mut vm, &modulo, -7, 2), Some(1));
+        assert_eq!(call(&mut vm, &modulo, 7, 0), None);
+        assert_eq!(call(&mut vm, &modulo, i64::MIN, -1), None);
+    }
+
+    #[test]
+    fn shifts_out_of_range_deopt() {
+        let mut vm = vm();
+        let left = compiled(&mut vm, "a << b");
+        assert_eq!(call(&mut vm, &left, 1, 62), Some(1 << 62));
+        assert_eq!(call(&mut vm, &left, 1, 63), None);
+        assert_eq!(call(&mut vm, &left, 1, 64), None);
+        assert_eq!(call(&mut vm, &left, 1, -1), None);
+
+        let right = compiled(&mut vm, "a >> b");
+        assert_eq!(call(&mut vm, &right, -8, 1), Some(-4));
+        assert_eq!(call(&mut vm, &right, -8, 64), None);
+        assert_eq!(call(&mut vm, &right, -8, -1), None);
+    }
+
+    #[test]
+    fn globals_are_only_stored_once_the_call_succeeds() {
+        let mut vm = vm();
+        run(&mut vm, "g = 5").unwrap();
+        let native = compiled(&mut vm, "g = a\n  a / b");
+        let slot = vm.global_slot("g");
+
+        assert_eq!(call(&mut vm, &native, 1, 0), None);
+        assert!(matches!(vm.get_global_slot(slot), Ok(Value::Int(5))));
+
+        assert_eq!(call(&mut vm, &native, 7, 2), Some(3));
+        assert!(matches!(vm.get_global_slot(slot), Ok(Value::Int(7))));
+    }
+
+    #[test]
+    fn running_out_of_fuel_around_compiled_calls_can_be_resumed() {
+        let source = script("a * b + 1", 2 * HOT_CALLS);
+        let mut unlimited = vm();
+        run(&mut unlimited, &source).unwrap();
+
+        // Enough to get f compiled, but not to finish.
+        let mut vm = vm();
+        vm.set_fuel(Some(10 * HOT_CALLS as u64));
+        assert!(matches!(
+            run(&mut vm, &source),
+            Err(RuntimeError::FuelExhausted)
+        ));
+        assert!(function(&mut vm, "f").is_compiled());
+
+        vm.set_fuel(None);
+        vm.run().unwrap();
+        assert_eq!(output(&mut vm), output(&mut unlimited));
+    }
+
+    #[test]
+    fn scripts_calling_compiled_code_can_be_interrupted() {
+        let mut vm = vm();
+        run(&mut vm, &script("a * b + 1", HOT_CALLS)).unwrap();
+        output(&mut vm);
+
+        vm.interrupt_handle().store(true, Ordering::Relaxed);
+        assert!(matches!(
+            run(&mut vm, "puts f(1, 2)"),
+            Err(RuntimeError::Interrupted)
+        ));
+        vm.run().unwrap();
+        assert_eq!(output(&mut vm), "3\n");
+    }
+}
@@ -1,16 +1,108 @@
-mod error;
+pub mod error;
 mod frame;
 mod gc;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod opcode;
+#[cfg(feature = "register-vm")]
+pub mod register;
+#[cfg(feature = "register-vm")]
+mod register_run;
+// The register VM only needs `check_budget` and `run_frame` from the stack VM.
+#[cfg_attr(feature = "register-vm", allow(dead_code))]
 mod run;
+#[cfg(test)]
+pub mod testing;
+#[allow(clippy::module_inception)]
 mod vm;
 
+use crate::compiler::bytecode;
 use crate::compiler::compile;
+use crate::compiler::error::{CompilerError, CompilerWarning};
+use crate::compiler::object::Function;
+use crate::compiler::optimizer::OptLevel;
+use crate::lexer::error::SyntaxError;
+use crate::vm::error::InterpretError;
 use crate::vm::vm::VM;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 
-pub fn interpret(source: &str) {
-    let fun = compile(source).unwrap();
+/// Bounds on how long and how deep a script may run, given on the command
+/// line.
+#[derive(Debug, Default)]
+pub struct Limits {
+    /// How many instructions the script may execute.
+    pub fuel: Option<u64>,
+    /// How long the script may run before it's interrupted.
+    pub timeout: Option<Duration>,
+    /// Call depth at which the stack overflows.
+    pub max_frames: Option<usize>,
+    /// Value stack size at which the stack overflows.
+    pub max_stack: Option<usize>,
+    /// Bytes the heap may hold before allocations fail.
+    pub max_heap: Option<usize>,
+}
+
+/// How the garbage collector runs, given on the command line.
+#[derive(Debug, Default)]
+pub struct GcOptions {
+    /// Collect before every allocation.
+    pub stress: bool,
+    /// How long one incremental collection step may run.
+    pub pause_budget: Option<Duration>,
+    /// Print what the collector did once the script stops.
+    pub print_stats: bool,
+}
+
+/// Loads either source code or a compiled `.fbc` file, told apart by the
+/// bytecode magic header. `opt_level` only applies to source code, and so
+/// do `warnings`.
+pub fn load(
+    input: &[u8],
+    opt_level: OptLevel,
+    warnings: &mut Vec<CompilerWarning>,
+) -> Result<Function, InterpretError> {
+    if bytecode::is_bytecode(input) {
+        return Ok(bytecode::deserialize(input)?);
+    }
+
+    let source = std::str::from_utf8(input)
+        .map_err(|_| CompilerError::Syntax(SyntaxError::UnexpectedChar))?;
+    Ok(compile(source, opt_level, warnings)?)
+}
 
+/// Runs a loaded script within `limits`.
+pub fn interpret(fun: Function, limits: &Limits, gc: &GcOptions) -> Result<(), InterpretError> {
     let mut vm = VM::new();
-    vm.interpret(fun).unwrap();
+    vm.heap_mut().set_stress(gc.stress);
+    if let Some(budget) = gc.pause_budget {
+        vm.heap_mut().set_pause_budget(budget);
+    }
+    vm.set_fuel(limits.fuel);
+    if let Some(max) = limits.max_frames {
+        vm.set_max_frames(max);
+    }
+    if let Some(max) = limits.max_stack {
+        vm.set_max_stack(max);
+    }
+    vm.set_max_heap_size(limits.max_heap);
+    if let Some(timeout) = limits.timeout {
+        let interrupt = vm.interrupt_handle();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            interrupt.store(true, Ordering::Relaxed);
+        });
+    }
+    let result = vm.interpret(fun);
+    if gc.print_stats {
+        eprintln!(
+            "gc: {}, {} objects in {} bytes left",
+            vm.gc_stats(),
+            vm.heap().object_count(),
+            vm.heap().bytes_allocated()
+        );
+    }
+    result?;
+    Ok(())
 }
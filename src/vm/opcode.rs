@@ -1,4 +1,5 @@
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     Return,
     Constant,
@@ -6,34 +7,92 @@ pub enum Opcode {
     Subtract,
     Multiply,
     Divide,
-    SetGlobal,
-    GetGlobal,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Negate,
+    BitNot,
     GetLocal,
     SetLocal,
     Closure,
     Call,
     Puts,
     Pop,
+    Jump,
+    JumpIfFalse,
+    ConstantLong,
+    GetLocalLong,
+    SetLocalLong,
+    ClosureLong,
+    SetLocalPop,
+    AddConstant,
+    GetGlobalSlot,
+    SetGlobalSlot,
+    TailCall,
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        match byte {
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        Some(match byte {
             0x00 => Opcode::Return,
             0x01 => Opcode::Constant,
             0x02 => Opcode::Add,
             0x03 => Opcode::Subtract,
             0x04 => Opcode::Multiply,
             0x05 => Opcode::Divide,
-            0x06 => Opcode::SetGlobal,
-            0x07 => Opcode::GetGlobal,
-            0x08 => Opcode::SetLocal,
-            0x09 => Opcode::GetLocal,
-            0x0a => Opcode::Closure,
-            0x0b => Opcode::Call,
-            0x0c => Opcode::Puts,
-            0x0d => Opcode::Pop,
-            _ => panic!("No opcode for byte: {}", byte),
+            0x06 => Opcode::Modulo,
+            0x07 => Opcode::Power,
+            0x08 => Opcode::BitAnd,
+            0x09 => Opcode::BitOr,
+            0x0a => Opcode::BitXor,
+            0x0b => Opcode::ShiftLeft,
+            0x0c => Opcode::ShiftRight,
+            0x0d => Opcode::Negate,
+            0x0e => Opcode::BitNot,
+            0x0f => Opcode::GetLocal,
+            0x10 => Opcode::SetLocal,
+            0x11 => Opcode::Closure,
+            0x12 => Opcode::Call,
+            0x13 => Opcode::Puts,
+            0x14 => Opcode::Pop,
+            0x15 => Opcode::Jump,
+            0x16 => Opcode::JumpIfFalse,
+            0x17 => Opcode::ConstantLong,
+            0x18 => Opcode::GetLocalLong,
+            0x19 => Opcode::SetLocalLong,
+            0x1a => Opcode::ClosureLong,
+            0x1b => Opcode::SetLocalPop,
+            0x1c => Opcode::AddConstant,
+            0x1d => Opcode::GetGlobalSlot,
+            0x1e => Opcode::SetGlobalSlot,
+            0x1f => Opcode::TailCall,
+            _ => return None,
+        })
+    }
+
+    /// How many operand bytes follow the opcode.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Opcode::Constant
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::Closure
+            | Opcode::Call
+            | Opcode::TailCall
+            | Opcode::SetLocalPop
+            | Opcode::AddConstant => 1,
+            Opcode::Jump | Opcode::JumpIfFalse => 2,
+            Opcode::ConstantLong
+            | Opcode::GetLocalLong
+            | Opcode::SetLocalLong
+            | Opcode::ClosureLong
+            | Opcode::GetGlobalSlot
+            | Opcode::SetGlobalSlot => 3,
+            _ => 0,
         }
     }
 }
@@ -0,0 +1,437 @@
+use crate::compiler::object::Function;
+use crate::compiler::value::{SizeFn, Value};
+use crate::compiler::verifier::{stack_depths, stack_effect};
+use crate::vm::error::RunResult;
+use crate::vm::opcode::Opcode;
+use std::fmt::{Debug, Formatter, Result};
+
+/// A register, numbered from the callee's slot at the base of the frame.
+/// Slot `n` of the stack VM becomes register `n`, so arguments keep their
+/// slots and calling conventions stay the same.
+pub type Register = usize;
+
+/// Where an instruction reads a value from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(Register),
+    Const(usize),
+}
+
+pub type BinaryOp = fn(Value, Value) -> RunResult<Value>;
+pub type UnaryOp = fn(Value) -> RunResult<Value>;
+
+#[derive(Clone, Copy)]
+pub enum Instruction {
+    Move {
+        dst: Register,
+        src: Operand,
+    },
+    /// `size`, if given, says how much room the result needs under the
+    /// heap limit.
+    Binary {
+        op: BinaryOp,
+        size: Option<SizeFn>,
+        dst: Register,
+        a: Operand,
+        b: Operand,
+    },
+    Unary {
+        op: UnaryOp,
+        dst: Register,
+        a: Operand,
+    },
+    GetGlobalSlot {
+        dst: Register,
+        slot: usize,
+    },
+    SetGlobalSlot {
+        slot: usize,
+        src: Operand,
+    },
+    Closure {
+        dst: Register,
+        fun: usize,
+    },
+    /// Calls the closure in `base` with the `argc` registers after it, and
+    /// leaves the result in `base`.
+    Call {
+        base: Register,
+        argc: u8,
+    },
+    /// Like `Call`, but the callee replaces the current frame.
+    TailCall {
+        base: Register,
+        argc: u8,
+    },
+    Puts {
+        src: Operand,
+    },
+    Jump {
+        target: usize,
+    },
+    JumpIfFalse {
+        cond: Register,
+        target: usize,
+    },
+    Return {
+        src: Operand,
+    },
+}
+
+impl Debug for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Instruction::Move { dst, src } => write!(f, "MOVE r{} {:?}", dst, src),
+            Instruction::Binary { dst, a, b, .. } => write!(f, "BINARY r{} {:?} {:?}", dst, a, b),
+            Instruction::Unary { dst, a, .. } => write!(f, "UNARY r{} {:?}", dst, a),
+            Instruction::GetGlobalSlot { dst, slot } => {
+                write!(f, "GET_GLOBAL_SLOT r{} g{}", dst, slot)
+            }
+            Instruction::SetGlobalSlot { slot, src } => {
+                write!(f, "SET_GLOBAL_SLOT g{} {:?}", slot, src)
+            }
+            Instruction::Closure { dst, fun } => write!(f, "CLOSURE r{} k{}", dst, fun),
+            Instruction::Call { base, argc } => write!(f, "CALL r{} {}", base, argc),
+            Instruction::TailCall { base, argc } => write!(f, "TAIL_CALL r{} {}", base, argc),
+            Instruction::Puts { src } => write!(f, "PUTS {:?}", src),
+            Instruction::Jump { target } => write!(f, "JUMP {}", target),
+            Instruction::JumpIfFalse { cond, target } => {
+                write!(f, "JUMP_IF_FALSE r{} {}", cond, target)
+            }
+            Instruction::Return { src } => write!(f, "RETURN {:?}", src),
+        }
+    }
+}
+
+/// A function's code for the register VM, along with how many registers
+/// its frame needs.
+#[derive(Debug, Clone)]
+pub struct RegisterChunk {
+    pub code: Vec<Instruction>,
+    pub registers: usize,
+}
+
+/// Translates the stack code of `fun` to register code, or returns `None` if
+/// the code doesn't verify, so that it can run on the stack VM instead.
+///
+/// The operand stack is tracked symbolically: pushing a constant or a local
+/// just records where the value lives, and instructions consuming it read
+/// it straight from there. Values only get moved into their own register
+/// when something needs them there, like a call, a jump or an overwrite of
+/// the register they're read from.
+pub fn translate(fun: &Function) -> Option<RegisterChunk> {
+    // Global slots were already checked against the script's names.
+    let depths = stack_depths(fun, usize::MAX).ok()?;
+    let bytes = fun.chunk().code();
+
+    let mut targets = vec![false; bytes.len() + 1];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (opcode, operand, next) = decode(bytes, offset)?;
+        if matches!(opcode, Opcode::Jump | Opcode::JumpIfFalse) {
+            targets[next + operand] = true;
+        }
+        offset = next;
+    }
+
+    // The callee and its arguments are already in place.
+    let params = *fun.arity() as usize + 1;
+    let mut translator = Translator {
+        code: vec![],
+        stack: vec![],
+        registers: params,
+    };
+    translator.reset(params);
+    let mut labels = vec![0; bytes.len() + 1];
+    let mut jumps = vec![];
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (opcode, operand, next) = decode(bytes, offset)?;
+        let depth = match depths[offset] {
+            Some(depth) => depth,
+            // Unreachable code.
+            None => {
+                labels[offset] = translator.code.len();
+                offset = next;
+                continue;
+            }
+        };
+
+        // Every path into a jump target has to agree on where values live.
+        if targets[offset] {
+            if translator.stack.len() == depth {
+                translator.flush();
+            }
+            translator.reset(depth);
+        }
+        labels[offset] = translator.code.len();
+
+        if matches!(opcode, Opcode::Jump | Opcode::JumpIfFalse) {
+            translator.flush();
+            jumps.push((translator.code.len(), next + operand));
+        }
+        translator.translate(opcode, operand);
+
+        // Code after a jump or return is only reachable through a label.
+        if matches!(opcode, Opcode::Jump | Opcode::Return | Opcode::TailCall) {
+            translator.stack.clear();
+        }
+        offset = next;
+    }
+    labels[bytes.len()] = translator.code.len();
+
+    for (index, target) in jumps {
+        match &mut translator.code[index] {
+            Instruction::Jump { target: label }
+            | Instruction::JumpIfFalse { target: label, .. } => *label = labels[target],
+            _ => unreachable!(),
+        }
+    }
+
+    Some(RegisterChunk {
+        code: translator.code,
+        registers: translator.registers,
+    })
+}
+
+struct Translator {
+    code: Vec<Instruction>,
+    /// Where each value on the stack VM's operand stack currently lives.
+    /// Slot `n` may only be read from register `k != n` while slot `k`
+    /// itself lives in register `k`, and `k` is always below `n`.
+    stack: Vec<Operand>,
+    registers: usize,
+}
+
+impl Translator {
+    fn translate(&mut self, opcode: Opcode, operand: usize) {
+        use Opcode::*;
+
+        let (pops, pushes) = stack_effect(opcode, operand);
+        self.registers = self
+            .registers
+            .max(self.stack.len() - pops + pushes)
+            .max(self.stack.len());
+
+        match opcode {
+            Constant | ConstantLong => self.stack.push(Operand::Const(operand)),
+            GetLocal | GetLocalLong => {
+                let value = self.stack[operand];
+                self.stack.push(value);
+            }
+            SetLocal | SetLocalLong | SetLocalPop => {
+                self.write(operand);
+                let src = *self.stack.last().unwrap();
+                self.code.push(Instruction::Move { dst: operand, src });
+                self.stack[operand] = Operand::Reg(operand);
+                if opcode == SetLocalPop {
+                    self.stack.pop();
+                }
+            }
+            Add => self.binary(|a, b| a + b),
+            Subtract => self.binary(|a, b| a - b),
+            Multiply => self.growing(|a, b| a * b, Value::product_size),
+            Divide => self.binary(|a, b| a / b),
+            Modulo => self.binary(|a, b| a % b),
+            Power => self.growing(Value::pow, Value::power_size),
+            BitAnd => self.binary(|a, b| a & b),
+            BitOr => self.binary(|a, b| a | b),
+            BitXor => self.binary(|a, b| a ^ b),
+            ShiftLeft => self.growing(|a, b| a << b, Value::shift_size),
+            ShiftRight => self.binary(|a, b| a >> b),
+            AddConstant => {
+                self.stack.push(Operand::Const(operand));
+                self.binary(|a, b| a + b);
+            }
+            Negate => self.unary(|a| -a),
+            BitNot => self.unary(|a| !a),
+            GetGlobalSlot => {
+                let dst = self.push();
+                self.code
+                    .push(Instruction::GetGlobalSlot { dst, slot: operand });
+            }
+            SetGlobalSlot => {
+                let src = self.stack.pop().unwrap();
+                self.code
+                    .push(Instruction::SetGlobalSlot { slot: operand, src });
+            }
+            Closure | ClosureLong => {
+                let dst = self.push();
+                self.code.push(Instruction::Closure { dst, fun: operand });
+            }
+            Call => {
+                let base = self.stack.len() - operand - 1;
+                self.flush();
+                self.stack.truncate(base);
+                self.push();
+                self.code.push(Instruction::Call {
+                    base,
+                    argc: operand as u8,
+                });
+            }
+            TailCall => {
+                let base = self.stack.len() - operand - 1;
+                self.flush();
+                self.code.push(Instruction::TailCall {
+                    base,
+                    argc: operand as u8,
+                });
+            }
+            Puts => {
+                let src = self.stack.pop().unwrap();
+                self.code.push(Instruction::Puts { src });
+            }
+            Pop => {
+                self.stack.pop();
+            }
+            Jump => self.code.push(Instruction::Jump { target: 0 }),
+            JumpIfFalse => self.code.push(Instruction::JumpIfFalse {
+                cond: self.stack.len() - 1,
+                target: 0,
+            }),
+            Return => {
+                let src = self.stack.pop().unwrap();
+                self.code.push(Instruction::Return { src });
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp) {
+        self.sized_binary(op, None);
+    }
+
+    /// A binary operator whose result can be much larger than its operands.
+    fn growing(&mut self, op: BinaryOp, size: SizeFn) {
+        self.sized_binary(op, Some(size));
+    }
+
+    fn sized_binary(&mut self, op: BinaryOp, size: Option<SizeFn>) {
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        let dst = self.push();
+        self.code.push(Instruction::Binary {
+            op,
+            size,
+            dst,
+            a,
+            b,
+        });
+    }
+
+    fn unary(&mut self, op: UnaryOp) {
+        let a = self.stack.pop().unwrap();
+        let dst = self.push();
+        self.code.push(Instruction::Unary { op, dst, a });
+    }
+
+    /// Pushes a value that will be written to its own register, returning
+    /// that register. Nothing below the top of the stack can refer to it.
+    fn push(&mut self) -> Register {
+        let dst = self.stack.len();
+        self.stack.push(Operand::Reg(dst));
+        self.registers = self.registers.max(dst + 1);
+        dst
+    }
+
+    /// Prepares for `register` being overwritten, by moving values still
+    /// read from it into their own registers first.
+    fn write(&mut self, register: Register) {
+        for slot in 0..self.stack.len() {
+            if slot != register && self.stack[slot] == Operand::Reg(register) {
+                self.code.push(Instruction::Move {
+                    dst: slot,
+                    src: Operand::Reg(register),
+                });
+                self.stack[slot] = Operand::Reg(slot);
+            }
+        }
+    }
+
+    /// Moves every value into its own register. Registers of slots living
+    /// elsewhere aren't read by anyone, so this can't clobber anything.
+    fn flush(&mut self) {
+        for slot in 0..self.stack.len() {
+            if self.stack[slot] != Operand::Reg(slot) {
+                let src = self.stack[slot];
+                self.code.push(Instruction::Move { dst: slot, src });
+                self.stack[slot] = Operand::Reg(slot);
+            }
+        }
+    }
+
+    /// Starts a block whose `depth` values all live in their own registers.
+    fn reset(&mut self, depth: usize) {
+        self.stack = (0..depth).map(Operand::Reg).collect();
+    }
+}
+
+fn decode(bytes: &[u8], offset: usize) -> Option<(Opcode, usize, usize)> {
+    let opcode = Opcode::from_byte(bytes[offset])?;
+    let next = offset + 1 + opcode.operand_len();
+    let operand = bytes
+        .get(offset + 1..next)?
+        .iter()
+        .fold(0, |operand, &byte| operand << 8 | byte as usize);
+    Some((opcode, operand, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::object::Gc;
+    use crate::vm::testing::{output, run, vm};
+
+    fn code(fun: &Function) -> Vec<String> {
+        let chunk = translate(fun).expect("doesn't translate");
+        chunk.code.iter().map(|i| format!("{:?}", i)).collect()
+    }
+
+    #[test]
+    fn values_are_read_from_where_they_live() {
+        let mut vm = vm();
+        run(&mut vm, "def f(a, b) do\n  a + b * 2\nend\n").unwrap();
+        let slot = vm.global_slot("f");
+        let fun = match vm.get_global_slot(slot) {
+            Ok(Value::Closure(closure)) => closure.fun,
+            _ => panic!("f isn't a function"),
+        };
+        assert_eq!(
+            code(&fun),
+            [
+                "BINARY r4 Reg(2) Const(0)",
+                "BINARY r3 Reg(1) Reg(4)",
+                "RETURN Reg(3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn code_that_doesnt_translate_runs_on_the_stack_vm() {
+        // Runs fine, but the trailing byte isn't an instruction.
+        let mut callee = Function::new();
+        let answer = callee.chunk_mut().add_constant(Value::Int(42));
+        callee.chunk_mut().write(Opcode::Constant);
+        callee.chunk_mut().write_byte(answer as u8);
+        callee.chunk_mut().write(Opcode::Return);
+        callee.chunk_mut().write_byte(0xff);
+        assert!(translate(&callee).is_none());
+
+        let mut script = Function::new();
+        let callee = script
+            .chunk_mut()
+            .add_constant(Value::Function(Gc::new(callee)));
+        for byte in [Opcode::Closure as u8, callee as u8, Opcode::Call as u8, 0] {
+            script.chunk_mut().write_byte(byte);
+        }
+        script.chunk_mut().write(Opcode::Puts);
+        script.chunk_mut().write(Opcode::Constant);
+        script.chunk_mut().write_byte(callee as u8);
+        script.chunk_mut().write(Opcode::Return);
+        assert!(translate(&script).is_some());
+
+        let mut vm = vm();
+        vm.interpret(script).unwrap();
+        assert_eq!(output(&mut vm), "42\n");
+    }
+}
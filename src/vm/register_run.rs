@@ -0,0 +1,131 @@
+use crate::compiler::object::Closure;
+use crate::compiler::value::Value;
+use crate::vm::error::{RunResult, RuntimeError};
+use crate::vm::register::{Instruction, Operand};
+use crate::vm::vm::VM;
+use std::io::Write;
+
+impl<W: Write> VM<W> {
+    /// Runs until the outermost frame returns, executing each function's
+    /// register code. A frame's registers are its window of the stack, so
+    /// frames, the garbage collector and the budget work just like they do
+    /// for the stack VM.
+    pub fn run(&mut self) -> RunResult<()> {
+        while !self.is_at_end() {
+            let frame = self.frame()?;
+            let fun = frame.closure().fun;
+            let base = *frame.stack_start();
+            let mut ip = *frame.ip();
+            let chunk = match fun.register_chunk() {
+                Some(chunk) => chunk,
+                // Code that doesn't translate runs on the stack VM, which
+                // expects a new frame's stack to end with its arguments.
+                None => {
+                    if ip == 0 {
+                        let args_end = base + *fun.arity() as usize + 1;
+                        self.stack_mut().truncate(args_end);
+                    }
+                    self.run_frame()?;
+                    continue;
+                }
+            };
+            let constants = fun.chunk().constants();
+
+            // Calls leave the caller's registers alone, but the callee's
+            // window may reach past the top of the stack.
+            self.reserve_stack(base + chunk.registers);
+
+            loop {
+                if let Err(error) = self.check_budget() {
+                    *self.frame_mut()?.ip_mut() = ip;
+                    return Err(error);
+                }
+
+                let read = |vm: &Self, operand| match operand {
+                    Operand::Reg(register) => vm.stack_value(base + register),
+                    Operand::Const(index) => constants[index].clone(),
+                };
+
+                let instruction = chunk.code[ip];
+                ip += 1;
+                match instruction {
+                    Instruction::Move { dst, src } => {
+                        self.set_stack_value(base + dst, read(self, src));
+                    }
+                    Instruction::Binary {
+                        op,
+                        size,
+                        dst,
+                        a,
+                        b,
+                    } => {
+                        let (a, b) = (read(self, a), read(self, b));
+                        if let Some(size) = size {
+                            self.reserve_digits(size(&a, &b))?;
+                        }
+                        self.set_stack_value(base + dst, op(a, b)?);
+                    }
+                    Instruction::Unary { op, dst, a } => {
+                        self.set_stack_value(base + dst, op(read(self, a))?);
+                    }
+                    Instruction::GetGlobalSlot { dst, slot } => {
+                        let value = self.get_global_slot(slot)?;
+                        self.set_stack_value(base + dst, value);
+                    }
+                    Instruction::SetGlobalSlot { slot, src } => {
+                        let value = read(self, src);
+                        self.set_global_slot(slot, value);
+                    }
+                    Instruction::Closure { dst, fun } => {
+                        let fun = match &constants[fun] {
+                            Value::Function(fun) => *fun,
+                            _ => return Err(RuntimeError::ArgumentTypes),
+                        };
+                        let closure = self.alloc(Closure::new(fun))?;
+                        self.set_stack_value(base + dst, Value::Closure(closure));
+                    }
+                    Instruction::Call { base: callee, argc } => {
+                        *self.frame_mut()?.ip_mut() = ip;
+                        self.call_value_at(base + callee, argc)?;
+                        break;
+                    }
+                    Instruction::TailCall { base: callee, argc } => {
+                        self.stack_mut().truncate(base + callee + argc as usize + 1);
+                        self.stack_mut().drain(base..base + callee);
+                        self.frames_mut().pop();
+                        self.call_value(argc)?;
+                        break;
+                    }
+                    Instruction::Puts { src } => {
+                        let value = read(self, src);
+                        writeln!(self.stdout_mut(), "{}", value).map_err(|_| RuntimeError::Io)?;
+                    }
+                    Instruction::Jump { target } => ip = target,
+                    Instruction::JumpIfFalse { cond, target } => {
+                        if self.stack()[base + cond].is_falsy() {
+                            ip = target;
+                        }
+                    }
+                    Instruction::Return { src } => {
+                        let result = read(self, src);
+                        self.frames_mut().pop();
+                        // Keep the caller's registers, so that it doesn't
+                        // have to grow its window back.
+                        let caller_end = match self.frames().last() {
+                            Some(caller) => match caller.closure().fun.register_chunk() {
+                                Some(chunk) => caller.stack_start() + chunk.registers,
+                                // The stack VM wants the result on top.
+                                None => 0,
+                            },
+                            None => 0,
+                        };
+                        self.stack_mut().truncate(caller_end.max(base + 1));
+                        self.set_stack_value(base, result);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,137 +1,475 @@
+use crate::compiler::object::{Closure, Function};
+use crate::compiler::value::Value;
 use crate::vm::error::{RunResult, RuntimeError};
 use crate::vm::opcode::Opcode;
 use crate::vm::vm::VM;
 use std::io::Write;
-use crate::compiler::object::Closure;
-use crate::compiler::value::Value;
+use std::sync::atomic::Ordering;
+
+/// The running frame's code and position, kept in locals by `run` so
+/// instructions don't look the frame up again for every byte. Reading past
+/// the end of the code, or a constant that isn't there, is an error rather
+/// than a panic, as unverified code can get here too.
+pub struct Cursor<'a> {
+    code: &'a [u8],
+    constants: &'a [Value],
+    ip: usize,
+    /// Where the frame's slots start on the stack.
+    base: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(fun: &'a Function, ip: usize, base: usize) -> Self {
+        Cursor {
+            code: fun.chunk().code(),
+            constants: fun.chunk().constants(),
+            ip,
+            base,
+        }
+    }
+
+    fn byte(&mut self) -> RunResult<u8> {
+        let byte = *self.code.get(self.ip).ok_or(RuntimeError::EndOfCode)?;
+        self.ip += 1;
+        Ok(byte)
+    }
+
+    fn short(&mut self) -> RunResult<usize> {
+        let high = self.byte()?;
+        let low = self.byte()?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+
+    /// Reads a one byte operand, or a three byte one for `*Long` opcodes.
+    fn operand(&mut self, long: bool) -> RunResult<usize> {
+        if !long {
+            return Ok(self.byte()? as usize);
+        }
+
+        let high = self.byte()?;
+        let mid = self.byte()?;
+        let low = self.byte()?;
+        Ok(u32::from_be_bytes([0, high, mid, low]) as usize)
+    }
+
+    fn constant(&mut self, long: bool) -> RunResult<&'a Value> {
+        let constants = self.constants;
+        let index = self.operand(long)?;
+        constants
+            .get(index)
+            .ok_or(RuntimeError::BadConstantIndex(index))
+    }
+}
+
+/// What `run` does once an instruction is done.
+pub enum Flow {
+    Next,
+    /// A call or return changed the running frame.
+    Switch,
+}
+
+type Handler<W> = fn(&mut VM<W>, &mut Cursor) -> RunResult<Flow>;
 
 impl<W: Write> VM<W> {
+    /// Instruction handlers, indexed by opcode byte.
+    const DISPATCH: [Handler<W>; 256] = {
+        let mut table = [Self::invalid_opcode as Handler<W>; 256];
+        table[Opcode::Return as usize] = Self::ret;
+        table[Opcode::Constant as usize] = |vm, cursor| vm.constant(cursor, false);
+        table[Opcode::ConstantLong as usize] = |vm, cursor| vm.constant(cursor, true);
+        table[Opcode::Add as usize] = Self::add;
+        table[Opcode::Subtract as usize] = Self::subtract;
+        table[Opcode::Multiply as usize] = Self::multiply;
+        table[Opcode::Divide as usize] = Self::divide;
+        table[Opcode::Modulo as usize] = Self::modulo;
+        table[Opcode::Power as usize] = Self::power;
+        table[Opcode::BitAnd as usize] = Self::bit_and;
+        table[Opcode::BitOr as usize] = Self::bit_or;
+        table[Opcode::BitXor as usize] = Self::bit_xor;
+        table[Opcode::ShiftLeft as usize] = Self::shift_left;
+        table[Opcode::ShiftRight as usize] = Self::shift_right;
+        table[Opcode::Negate as usize] = Self::negate;
+        table[Opcode::BitNot as usize] = Self::bit_not;
+        table[Opcode::GetGlobalSlot as usize] = Self::get_global_by_slot;
+        table[Opcode::SetGlobalSlot as usize] = Self::set_global_by_slot;
+        table[Opcode::SetLocal as usize] = |vm, cursor| vm.set_local(cursor, false);
+        table[Opcode::SetLocalLong as usize] = |vm, cursor| vm.set_local(cursor, true);
+        table[Opcode::SetLocalPop as usize] = Self::set_local_pop;
+        table[Opcode::GetLocal as usize] = |vm, cursor| vm.get_local(cursor, false);
+        table[Opcode::GetLocalLong as usize] = |vm, cursor| vm.get_local(cursor, true);
+        table[Opcode::Puts as usize] = Self::puts;
+        table[Opcode::Closure as usize] = |vm, cursor| vm.closure(cursor, false);
+        table[Opcode::ClosureLong as usize] = |vm, cursor| vm.closure(cursor, true);
+        table[Opcode::Call as usize] = Self::call_instr;
+        table[Opcode::TailCall as usize] = Self::tail_call;
+        table[Opcode::Pop as usize] = Self::pop_instr;
+        table[Opcode::Jump as usize] = Self::jump;
+        table[Opcode::JumpIfFalse as usize] = Self::jump_if_false;
+        table[Opcode::AddConstant as usize] = Self::add_constant;
+        table
+    };
+
+    /// Runs until the outermost frame returns. `Interrupted` and
+    /// `FuelExhausted` stop before an instruction is read, so calling `run`
+    /// again (after adding fuel) picks up where it left off.
+    #[cfg(not(feature = "register-vm"))]
     pub fn run(&mut self) -> RunResult<()> {
         while !self.is_at_end() {
-            let instruction = Opcode::from(self.read_byte()?);
-            match instruction {
-                Opcode::Constant => self.constant()?,
-                Opcode::Add => self.add()?,
-                Opcode::Subtract => self.subtract()?,
-                Opcode::Multiply => self.multiply()?,
-                Opcode::Divide => self.divide()?,
-                Opcode::SetGlobal => self.set_global()?,
-                Opcode::GetGlobal => self.get_global()?,
-                Opcode::SetLocal => self.set_local()?,
-                Opcode::GetLocal => self.get_local()?,
-                Opcode::Return => self.ret()?,
-                Opcode::Puts => self.puts()?,
-                Opcode::Closure => self.closure()?,
-                Opcode::Call => self.call_instr()?,
-                Opcode::Pop => {
-                    self.pop()?;
-                }
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Runs the current frame until a call or return switches frames.
+    pub fn run_frame(&mut self) -> RunResult<()> {
+        let frame = self.frame()?;
+        let fun = frame.closure().fun;
+        let mut cursor = Cursor::new(&fun, *frame.ip(), *frame.stack_start());
+
+        loop {
+            if let Err(error) = self.check_budget() {
+                *self.frame_mut()?.ip_mut() = cursor.ip;
+                return Err(error);
+            }
+
+            let opcode = cursor.byte()?;
+            if let Flow::Switch = Self::DISPATCH[opcode as usize](self, &mut cursor)? {
+                return Ok(());
             }
         }
+    }
+
+    pub fn check_budget(&mut self) -> RunResult<()> {
+        // Only write to the flag once it's been set.
+        if self.interrupt().load(Ordering::Relaxed)
+            && self.interrupt().swap(false, Ordering::Relaxed)
+        {
+            return Err(RuntimeError::Interrupted);
+        }
+
+        if let Some(fuel) = self.fuel_mut() {
+            if *fuel == 0 {
+                return Err(RuntimeError::FuelExhausted);
+            }
+            *fuel -= 1;
+        }
         Ok(())
     }
 
-    fn constant(&mut self) -> RunResult<()> {
-        let constant = self.read_constant()?.clone();
-        self.push(constant);
+    /// Saves the cursor's position, before a call leaves the frame.
+    fn save_ip(&mut self, cursor: &Cursor) -> RunResult<()> {
+        *self.frame_mut()?.ip_mut() = cursor.ip;
         Ok(())
     }
 
-    fn add(&mut self) -> RunResult<()> {
+    fn invalid_opcode(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        Err(RuntimeError::InvalidOpcode(cursor.code[cursor.ip - 1]))
+    }
+
+    fn constant(&mut self, cursor: &mut Cursor, long: bool) -> RunResult<Flow> {
+        let constant = cursor.constant(long)?.clone();
+        self.push(constant);
+        Ok(Flow::Next)
+    }
+
+    fn add(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        self.binary(|a, b| a + b, i64::checked_add, None)?;
+        Ok(Flow::Next)
+    }
+
+    fn add_constant(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let b = cursor.constant(false)?.clone();
+        self.push(b);
+        self.add(cursor)
+    }
+
+    fn subtract(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        self.binary(|a, b| a - b, i64::checked_sub, None)?;
+        Ok(Flow::Next)
+    }
+
+    fn multiply(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        self.binary(|a, b| a * b, i64::checked_mul, Some(Value::product_size))?;
+        Ok(Flow::Next)
+    }
+
+    fn divide(&mut self, _: &mut Cursor) -> RunResult<Flow> {
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a + b);
-        Ok(())
+        self.push((a / b)?);
+        Ok(Flow::Next)
     }
 
-    fn subtract(&mut self) -> RunResult<()> {
+    fn modulo(&mut self, _: &mut Cursor) -> RunResult<Flow> {
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a - b);
-        Ok(())
+        self.push((a % b)?);
+        Ok(Flow::Next)
     }
 
-    fn multiply(&mut self) -> RunResult<()> {
+    fn power(&mut self, _: &mut Cursor) -> RunResult<Flow> {
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a * b);
-        Ok(())
+        self.reserve_digits(a.power_size(&b))?;
+        self.push(a.pow(b)?);
+        Ok(Flow::Next)
     }
 
-    fn divide(&mut self) -> RunResult<()> {
+    fn bit_and(&mut self, _: &mut Cursor) -> RunResult<Flow> {
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(a / b);
-        Ok(())
+        self.push((a & b)?);
+        Ok(Flow::Next)
     }
 
-    fn set_global(&mut self) -> RunResult<()> {
-        if let Ok(value) = self.pop() {
-            let var_name = self.read_string()?;
-            self.globals_mut().insert(var_name, value);
-            return Ok(());
-        }
+    fn bit_or(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push((a | b)?);
+        Ok(Flow::Next)
+    }
 
-        Err(RuntimeError::BadStackIndex(10, self.stack().len())) // TODO: 10.
+    fn bit_xor(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push((a ^ b)?);
+        Ok(Flow::Next)
     }
 
-    fn get_global(&mut self) -> RunResult<()> {
-        let name = self.read_string()?;
+    fn shift_left(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.reserve_digits(a.shift_size(&b))?;
+        self.push((a << b)?);
+        Ok(Flow::Next)
+    }
 
-        if let Some(value) = self.globals().get(&name).cloned() {
-            self.push(value);
-            return Ok(());
-        }
+    fn shift_right(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push((a >> b)?);
+        Ok(Flow::Next)
+    }
 
-        Err(RuntimeError::UndefinedGlobal(name))
+    fn negate(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let a = self.pop()?;
+        self.push((-a)?);
+        Ok(Flow::Next)
+    }
+
+    fn bit_not(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        let a = self.pop()?;
+        self.push((!a)?);
+        Ok(Flow::Next)
     }
 
-    fn set_local(&mut self) -> RunResult<()> {
+    fn get_global_by_slot(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let value = self.get_global_slot(cursor.operand(true)?)?;
+        self.push(value);
+        Ok(Flow::Next)
+    }
+
+    fn set_global_by_slot(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let value = self.pop()?;
+        self.set_global_slot(cursor.operand(true)?, value);
+        Ok(Flow::Next)
+    }
+
+    fn set_local(&mut self, cursor: &mut Cursor, long: bool) -> RunResult<Flow> {
         let value = self.peek()?.clone();
-        let start = *self.frame()?.stack_start();
-        let slot = self.read_byte()? as usize;
-        self.stack_mut()[start + slot] = value;
-        Ok(())
+        let index = cursor.base + cursor.operand(long)?;
+
+        let len = self.stack().len();
+        let slot = self
+            .stack_mut()
+            .get_mut(index)
+            .ok_or(RuntimeError::BadStackIndex(index, len))?;
+        *slot = value;
+        Ok(Flow::Next)
+    }
+
+    fn set_local_pop(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let value = self.stack_mut().pop().ok_or(RuntimeError::StackEmpty)?;
+        let index = cursor.base + cursor.operand(false)?;
+
+        let len = self.stack().len();
+        match self.stack_mut().get_mut(index) {
+            Some(slot) => {
+                *slot = value;
+                Ok(Flow::Next)
+            }
+            None => Err(RuntimeError::BadStackIndex(index, len)),
+        }
     }
 
-    fn get_local(&mut self) -> RunResult<()> {
-        let start = *self.frame()?.stack_start();
-        let slot = self.read_byte()? as usize;
-        let index = start + slot;
+    fn get_local(&mut self, cursor: &mut Cursor, long: bool) -> RunResult<Flow> {
+        let index = cursor.base + cursor.operand(long)?;
 
         if let Some(value) = self.stack().get(index).cloned() {
             self.stack_mut().push(value);
-            return Ok(());
+            return Ok(Flow::Next);
         }
 
         Err(RuntimeError::BadStackIndex(index, self.stack().len()))
     }
 
-    fn ret(&mut self) -> RunResult<()> {
-        if let Some(frame) = self.frames_mut().pop() {
-            let result = self.pop()?;
-            self.stack_mut().truncate(*frame.stack_start());
-            self.push(result);
-        }
-        Ok(())
+    fn pop_instr(&mut self, _: &mut Cursor) -> RunResult<Flow> {
+        self.stack_mut().pop().ok_or(RuntimeError::StackEmpty)?;
+        Ok(Flow::Next)
     }
 
-    fn puts(&mut self) -> RunResult<()> {
+    fn ret(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let result = self.pop()?;
+        self.frames_mut().pop();
+        self.stack_mut().truncate(cursor.base);
+        self.push(result);
+        Ok(Flow::Switch)
+    }
+
+    fn puts(&mut self, _: &mut Cursor) -> RunResult<Flow> {
         let popped = self.pop()?;
-        writeln!(self.stdout_mut(), "{}", popped);
-        Ok(())
+        writeln!(self.stdout_mut(), "{}", popped).map_err(|_| RuntimeError::Io)?;
+        Ok(Flow::Next)
     }
 
-    fn closure(&mut self) -> RunResult<()> {
-        let closure = Closure::new(self.read_function()?);
-        let clos = self.alloc(closure);
+    fn closure(&mut self, cursor: &mut Cursor, long: bool) -> RunResult<Flow> {
+        let fun = match cursor.constant(long)? {
+            Value::Function(fun) => *fun,
+            _ => return Err(RuntimeError::ArgumentTypes),
+        };
+        let clos = self.alloc(Closure::new(fun))?;
         self.push(Value::Closure(clos));
-        Ok(())
+        Ok(Flow::Next)
     }
 
-    fn call_instr(&mut self) -> RunResult<()> {
-        let arity = self.read_byte()?;
+    fn jump(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let offset = cursor.short()?;
+        cursor.ip += offset;
+        Ok(Flow::Next)
+    }
+
+    fn jump_if_false(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let offset = cursor.short()?;
+        if self.peek()?.is_falsy() {
+            cursor.ip += offset;
+        }
+        Ok(Flow::Next)
+    }
+
+    fn call_instr(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let arity = cursor.byte()?;
+        self.save_ip(cursor)?;
         self.call_value(arity)?;
-        Ok(())
+        Ok(Flow::Switch)
+    }
+
+    /// Moves the callee and its arguments down over the current frame, and
+    /// calls it in the frame's place.
+    fn tail_call(&mut self, cursor: &mut Cursor) -> RunResult<Flow> {
+        let arity = cursor.byte()?;
+        let callee = self.stack().len() - (arity as usize + 1);
+        self.stack_mut().drain(cursor.base..callee);
+        self.frames_mut().pop();
+        self.call_value(arity)?;
+        Ok(Flow::Switch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::testing::{output, run, vm};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn running_out_of_fuel_can_be_resumed() {
+        let source: String = (1..=20).map(|i| format!("puts {}\n", i)).collect();
+        let mut unlimited = vm();
+        run(&mut unlimited, &source).unwrap();
+
+        let mut vm = vm();
+        vm.set_fuel(Some(10));
+        assert!(matches!(
+            run(&mut vm, &source),
+            Err(RuntimeError::FuelExhausted)
+        ));
+        assert_eq!(vm.fuel(), Some(0));
+        let partial = output(&mut vm);
+        assert!(!partial.is_empty());
+
+        vm.set_fuel(None);
+        vm.run().unwrap();
+        assert_eq!(partial + &output(&mut vm), output(&mut unlimited));
+    }
+
+    /// Runs a script made of `code`, with a single constant.
+    fn unverified(code: &[u8]) -> RunResult<()> {
+        let mut fun = Function::new();
+        fun.chunk_mut().add_constant(Value::Int(1));
+        for &byte in code {
+            fun.chunk_mut().write_byte(byte);
+        }
+        vm().interpret(fun)
+    }
+
+    #[test]
+    fn unverified_code_with_an_invalid_opcode_is_an_error() {
+        assert!(matches!(
+            unverified(&[0xff]),
+            Err(RuntimeError::InvalidOpcode(0xff))
+        ));
+    }
+
+    #[test]
+    fn unverified_code_with_bad_operands_is_an_error() {
+        let constant = Opcode::Constant as u8;
+        assert!(matches!(
+            unverified(&[constant]),
+            Err(RuntimeError::EndOfCode)
+        ));
+        assert!(matches!(
+            unverified(&[constant, 7]),
+            Err(RuntimeError::BadConstantIndex(7))
+        ));
+        assert!(matches!(
+            unverified(&[constant, 0, Opcode::SetLocal as u8, 9]),
+            Err(RuntimeError::BadStackIndex(9, 2))
+        ));
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_space() {
+        let mut vm = vm();
+        vm.set_max_frames(2);
+        vm.set_max_stack(32);
+
+        // Dividing by zero is the only way the countdown can stop.
+        let source =
+            "def countdown(n) do\n  x = 1 / n\n  countdown(n - 1)\nend\ncountdown(1000000)";
+        assert!(matches!(
+            run(&mut vm, source),
+            Err(RuntimeError::DivisionByZero)
+        ));
+        assert_eq!(vm.frames().len(), 2);
+    }
+
+    #[test]
+    fn another_thread_can_interrupt_a_script() {
+        let mut vm = vm();
+        let interrupt = vm.interrupt_handle();
+        let timer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+
+        let result = run(&mut vm, "def spin(n) do\n  spin(n + 1)\nend\nspin(0)");
+        timer.join().unwrap();
+        assert!(matches!(result, Err(RuntimeError::Interrupted)));
+        // The flag is cleared once seen, so the script can be resumed.
+        assert!(!vm.interrupt().load(Ordering::Relaxed));
+        vm.set_fuel(Some(100));
+        assert!(matches!(vm.run(), Err(RuntimeError::FuelExhausted)));
     }
 }
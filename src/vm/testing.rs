@@ -0,0 +1,46 @@
+//! Running scripts from tests, with their output captured, and building
+//! functions the compiler wouldn't.
+
+use crate::compiler::compile;
+use crate::compiler::object::{Function, Gc};
+use crate::compiler::optimizer::OptLevel;
+use crate::compiler::value::Value;
+use crate::vm::error::RunResult;
+use crate::vm::opcode::Opcode;
+use crate::vm::vm::VM;
+
+/// A VM printing to a buffer instead of stdout.
+pub fn vm() -> VM<Vec<u8>> {
+    VM::with_stdout(vec![])
+}
+
+/// Compiles `source` at `-O1` and runs it on `vm`.
+pub fn run(vm: &mut VM<Vec<u8>>, source: &str) -> RunResult<()> {
+    run_at(vm, source, OptLevel::O1)
+}
+
+pub fn run_at(vm: &mut VM<Vec<u8>>, source: &str, opt_level: OptLevel) -> RunResult<()> {
+    let fun = compile(source, opt_level, &mut vec![]).expect("test script doesn't compile");
+    vm.interpret(fun)
+}
+
+/// Takes what `vm` printed so far.
+pub fn output(vm: &mut VM<Vec<u8>>) -> String {
+    String::from_utf8(std::mem::take(vm.stdout_mut())).unwrap()
+}
+
+/// Functions that just return, each holding the next in its constants,
+/// `depth` levels deep.
+pub fn nested_functions(depth: usize) -> Function {
+    let mut fun = Function::new();
+    fun.chunk_mut().write(Opcode::Return);
+    for _ in 1..depth {
+        let mut outer = Function::new();
+        outer.chunk_mut().write(Opcode::Return);
+        outer
+            .chunk_mut()
+            .add_constant(Value::Function(Gc::new(fun)));
+        fun = outer;
+    }
+    fun
+}
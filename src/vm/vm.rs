@@ -1,15 +1,45 @@
-use crate::compiler::chunk::Chunk;
+#[cfg(feature = "nan-boxing")]
+use crate::compiler::nanbox::PackedValue;
 use crate::compiler::object::{Closure, Function, Gc};
-use crate::compiler::value::Value;
-use crate::vm::error::{RunResult, RuntimeError};
+use crate::compiler::value::{SizeFn, Value};
+use crate::vm::error::{FrameSummary, RunResult, RuntimeError};
 use crate::vm::frame::CallFrame;
+use crate::vm::gc::Heap;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::io::{stdout, Stdout, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Call depth at which `StackOverflow` is raised by default.
+const DEFAULT_MAX_FRAMES: usize = 10_000;
+
+/// Value stack size at which `StackOverflow` is raised by default, enough
+/// for every frame to use all of its 256 local slots.
+const DEFAULT_MAX_STACK: usize = DEFAULT_MAX_FRAMES * (u8::MAX as usize + 1);
+
+/// Longest cycle of calls `stack_trace` folds together.
+const MAX_TRACE_CYCLE: usize = 4;
+
+/// What the value stack holds: plain values, unless `nan-boxing` packs each
+/// of them into 8 bytes.
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+#[cfg(feature = "nan-boxing")]
+pub type Slot = PackedValue;
 
 pub struct VM<W: Write> {
-    stack: Vec<Value>,
+    stack: Vec<Slot>,
     frames: Vec<CallFrame>,
-    globals: HashMap<String, Value>,
+    /// Global values by slot, `None` until they're first assigned.
+    globals: Vec<Option<Value>>,
+    global_names: Vec<String>,
+    global_slots: HashMap<String, usize>,
+    heap: Heap,
+    fuel: Option<u64>,
+    max_frames: usize,
+    max_stack: usize,
+    interrupt: Arc<AtomicBool>,
     stdout: W,
 }
 
@@ -24,80 +54,150 @@ impl<W: Write> VM<W> {
         VM {
             stack: Vec::with_capacity(u8::MAX as usize),
             frames: Vec::with_capacity(u8::MAX as usize),
-            globals: HashMap::new(),
+            globals: vec![],
+            global_names: vec![],
+            global_slots: HashMap::new(),
+            heap: Heap::new(),
+            fuel: None,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            interrupt: Arc::new(AtomicBool::new(false)),
             stdout,
         }
     }
 
-    pub fn interpret(&mut self, fun: Function) -> RunResult<()> {
-        let closure = self.alloc(Closure::new(Gc::new(fun)).clone());
+    pub fn interpret(&mut self, mut fun: Function) -> RunResult<()> {
+        self.link_globals(&mut fun);
+        let fun = Gc::new(fun);
+        let closure = self.alloc(Closure::new(fun))?;
         self.push(Value::Closure(closure));
+        // Only adopt the script once it's reachable from the stack.
+        self.adopt_function(fun);
         self.call_value(0)?;
         self.run()
     }
 
-    pub fn call_value(&mut self, arity: u8) -> RunResult<()> {
-        let frame_start = self.stack.len() - (arity + 1) as usize;
-        let callee = self.stack[frame_start].clone();
-
-        match callee {
-            Value::Closure(c) => self.call(c, arity)?,
-            _ => return Err(RuntimeError::InvalidCallee),
-        };
-
-        Ok(())
+    /// Gives the script's globals their slots in this VM, which already
+    /// knows the globals of any script it ran before. The script's code is
+    /// renumbered if they don't line up.
+    fn link_globals(&mut self, fun: &mut Function) {
+        let slots: Vec<usize> = fun
+            .global_names()
+            .iter()
+            .map(|name| self.global_slot(name))
+            .collect();
+        if slots.iter().enumerate().any(|(i, &slot)| i != slot) {
+            remap_global_slots(fun, &slots);
+        }
     }
 
-    fn call(&mut self, closure: Gc<Closure>, arity: u8) -> RunResult<()> {
-        if arity != *closure.fun.arity() {
-            return Err(RuntimeError::IncorrectArity);
+    /// The slot of the global `name`, adding one if it's new.
+    pub fn global_slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
         }
 
-        let last = self.stack.len();
-        let frame_start = last - (arity + 1) as usize;
-        self.frames.push(CallFrame::new(closure, frame_start));
-        Ok(())
+        self.globals.push(None);
+        self.global_names.push(name.to_string());
+        self.global_slots
+            .insert(name.to_string(), self.global_names.len() - 1);
+        self.global_names.len() - 1
     }
 
-    pub fn read_string(&mut self) -> RunResult<String> {
-        match self.read_constant()? {
-            Value::String(s) => Ok(s.clone()),
-            _ => Err(RuntimeError::ArgumentTypes),
-        }
+    pub fn get_global_slot(&self, slot: usize) -> RunResult<Value> {
+        self.globals[slot]
+            .clone()
+            .ok_or_else(|| RuntimeError::UndefinedGlobal(self.global_names[slot].clone()))
     }
 
-    pub fn read_function(&mut self) -> RunResult<Gc<Function>> {
-        match self.read_constant()? {
-            Value::Function(fun) => Ok(fun.clone()),
-            _ => Err(RuntimeError::ArgumentTypes),
-        }
+    pub fn set_global_slot(&mut self, slot: usize, value: Value) {
+        self.globals[slot] = Some(value);
     }
 
-    pub fn read_constant(&mut self) -> RunResult<&Value> {
-        let constant_index = self.read_byte()?;
-        Ok(self.current_chunk()?.read_constant(constant_index as usize))
+    /// Calls the callee below the top `arity` values.
+    pub fn call_value(&mut self, arity: u8) -> RunResult<()> {
+        let frame_start = self.stack.len() - (arity as usize + 1);
+        self.call_value_at(frame_start, arity)
     }
 
-    pub fn read_byte(&mut self) -> RunResult<u8> {
-        let index = *self.frame()?.ip();
-        let byte = self.current_chunk()?.code()[index];
-        *self.frame_mut()?.ip_mut() += 1;
-        Ok(byte)
+    /// Calls the callee at `frame_start`, with the `arity` values after it
+    /// as arguments. Anything above them is left alone, for the callee to
+    /// overwrite.
+    pub fn call_value_at(&mut self, frame_start: usize, arity: u8) -> RunResult<()> {
+        match self.stack_value(frame_start) {
+            Value::Closure(c) => self.call(c, frame_start, arity),
+            _ => Err(RuntimeError::InvalidCallee),
+        }
     }
 
-    pub fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    fn call(&mut self, closure: Gc<Closure>, frame_start: usize, arity: u8) -> RunResult<()> {
+        if arity != *closure.fun.arity() {
+            return Err(RuntimeError::IncorrectArity);
+        }
+
+        if self.frames.len() >= self.max_frames || self.stack.len() >= self.max_stack {
+            return Err(RuntimeError::StackOverflow(self.stack_trace()));
+        }
+
+        #[cfg(feature = "jit")]
+        if let Some(native) = closure.fun.native_code() {
+            if self.call_native(&native, frame_start) {
+                return Ok(());
+            }
+        }
+
+        self.frames.push(CallFrame::new(closure, frame_start));
+        Ok(())
     }
 
-    pub fn peek(&mut self) -> RunResult<&Value> {
-        self.stack.last().ok_or(RuntimeError::StackEmpty)
+    /// The active frames, innermost first, with recursion folded together.
+    /// Cycles of up to `MAX_TRACE_CYCLE` functions are recognized, so mutual
+    /// recursion folds just like direct recursion does.
+    pub fn stack_trace(&self) -> Vec<FrameSummary> {
+        let names: Vec<&str> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| match frame.closure().fun.name() {
+                "" => "<script>",
+                name => name,
+            })
+            .collect();
+
+        let mut trace = vec![];
+        let mut i = 0;
+        while i < names.len() {
+            // Pick the cycle length that folds away the most frames.
+            let (len, calls) = (1..=MAX_TRACE_CYCLE)
+                .map(|len| {
+                    let cycle = &names[i..(i + len).min(names.len())];
+                    let calls = names[i..]
+                        .chunks(len)
+                        .take_while(|chunk| *chunk == cycle)
+                        .count();
+                    (len, calls)
+                })
+                .max_by_key(|&(len, calls)| (if calls > 1 { len * calls } else { 0 }, Reverse(len)))
+                .filter(|&(_, calls)| calls > 1)
+                .unwrap_or((1, 1));
+
+            trace.push(FrameSummary {
+                functions: names[i..i + len]
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                calls,
+            });
+            i += len * calls;
+        }
+        trace
     }
 
-    pub fn pop(&mut self) -> RunResult<Value> {
-        self.stack.pop().ok_or(RuntimeError::StackEmpty)
+    pub fn peek(&mut self) -> RunResult<&Slot> {
+        self.stack.last().ok_or(RuntimeError::StackEmpty)
     }
 
-    pub fn stack_mut(&mut self) -> &mut Vec<Value> {
+    pub fn stack_mut(&mut self) -> &mut Vec<Slot> {
         &mut self.stack
     }
 
@@ -105,7 +205,7 @@ impl<W: Write> VM<W> {
         self.frames.is_empty()
     }
 
-    pub fn stack(&self) -> &Vec<Value> {
+    pub fn stack(&self) -> &Vec<Slot> {
         &self.stack
     }
 
@@ -117,23 +217,143 @@ impl<W: Write> VM<W> {
         self.frames.last_mut().ok_or(RuntimeError::FrameEmpty)
     }
 
+    pub fn frames(&self) -> &Vec<CallFrame> {
+        &self.frames
+    }
+
     pub fn frames_mut(&mut self) -> &mut Vec<CallFrame> {
         &mut self.frames
     }
 
-    pub fn globals(&self) -> &HashMap<String, Value> {
+    pub fn globals(&self) -> &Vec<Option<Value>> {
         &self.globals
     }
 
-    pub fn globals_mut(&mut self) -> &mut HashMap<String, Value> {
-        &mut self.globals
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Sets the call depth at which calls fail with `StackOverflow`.
+    pub fn set_max_frames(&mut self, max: usize) {
+        self.max_frames = max;
+    }
+
+    /// Sets the value stack size at which calls fail with `StackOverflow`.
+    /// It's only checked on calls, so the innermost frame may briefly go
+    /// past it while evaluating an expression.
+    pub fn set_max_stack(&mut self, max: usize) {
+        self.max_stack = max;
+    }
+
+    /// Limits how many more instructions `run` may execute, or lifts the
+    /// limit with `None`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    #[cfg(test)]
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn fuel_mut(&mut self) -> &mut Option<u64> {
+        &mut self.fuel
+    }
+
+    /// A flag another thread can set to stop `run` before its next
+    /// instruction. It's cleared again once the VM has seen it.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn interrupt(&self) -> &AtomicBool {
+        &self.interrupt
     }
 
     pub fn stdout_mut(&mut self) -> &mut W {
         &mut self.stdout
     }
+}
+
+fn remap_global_slots(fun: &mut Function, slots: &[usize]) {
+    fun.chunk_mut().remap_global_slots(slots);
+
+    let nested: Vec<Gc<Function>> = fun
+        .chunk()
+        .constants()
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Function(nested) => Some(*nested),
+            _ => None,
+        })
+        .collect();
+    for mut nested in nested {
+        remap_global_slots(&mut nested, slots);
+    }
+}
 
-    fn current_chunk(&self) -> RunResult<&Chunk> {
-        Ok(&self.frame()?.closure().fun.chunk())
+// Converting between values and slots is a no-op without `nan-boxing`.
+#[allow(clippy::useless_conversion)]
+impl<W: Write> VM<W> {
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value.into());
+    }
+
+    pub fn pop(&mut self) -> RunResult<Value> {
+        self.stack
+            .pop()
+            .map(Value::from)
+            .ok_or(RuntimeError::StackEmpty)
+    }
+
+    /// Replaces the top two values with `op` applied to them. With
+    /// `nan-boxing`, two inline ints are combined by `int_op` instead,
+    /// without unpacking them, unless that overflows. Otherwise `size`, if
+    /// given, says how much room the result needs under the heap limit.
+    pub fn binary(
+        &mut self,
+        op: fn(Value, Value) -> RunResult<Value>,
+        _int_op: fn(i64, i64) -> Option<i64>,
+        size: Option<SizeFn>,
+    ) -> RunResult<()> {
+        #[cfg(feature = "nan-boxing")]
+        if let [.., a, b] = &self.stack[..] {
+            let result = a.as_int().zip(b.as_int());
+            if let Some(n) = result.and_then(|(a, b)| _int_op(a, b)) {
+                self.stack.pop();
+                self.stack.pop();
+                self.push(Value::Int(n));
+                return Ok(());
+            }
+        }
+
+        let b = self.pop()?;
+        let a = self.pop()?;
+        if let Some(size) = size {
+            self.reserve_digits(size(&a, &b))?;
+        }
+        self.push(op(a, b)?);
+        Ok(())
+    }
+
+    pub fn stack_value(&self, index: usize) -> Value {
+        self.stack[index].clone().into()
+    }
+
+    #[cfg(feature = "register-vm")]
+    pub fn set_stack_value(&mut self, index: usize, value: Value) {
+        self.stack[index] = value.into();
+    }
+
+    /// Grows the stack to at least `len` slots, filling new ones with false.
+    #[cfg(feature = "register-vm")]
+    pub fn reserve_stack(&mut self, len: usize) {
+        if self.stack.len() < len {
+            self.stack.resize(len, Value::Bool(false).into());
+        }
     }
 }
@@ -0,0 +1,95 @@
+//! Compiles every script in tests/scripts to C with `fusion build`, and
+//! checks the binaries do just what the interpreter does.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn fusion(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fusion"))
+        .args(args)
+        .output()
+        .expect("can't run fusion")
+}
+
+fn has_c_compiler() -> bool {
+    Command::new("cc")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<_> = fs::read_dir(dir)
+        .expect("can't read tests/scripts")
+        .map(|entry| entry.expect("can't read tests/scripts").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "fu"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+#[cfg_attr(
+    any(feature = "print-code", feature = "trace-gc"),
+    ignore = "the interpreter prints its own diagnostics along with the output"
+)]
+fn compiled_scripts_behave_like_interpreted_ones() {
+    assert!(has_c_compiler(), "building the scripts needs `cc`");
+
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("aot");
+    fs::create_dir_all(&out).expect("can't create the output directory");
+    for script in scripts() {
+        let name = script.file_stem().unwrap().to_string_lossy().into_owned();
+        let script = script.to_str().unwrap();
+        for opt_level in ["-O0", "-O1"] {
+            let c_file = out.join(format!("{}{}.c", name, opt_level));
+            let binary = out.join(format!("{}{}", name, opt_level));
+
+            let build = fusion(&["build", opt_level, script, "-o", c_file.to_str().unwrap()]);
+            assert!(
+                build.status.success(),
+                "{} {} doesn't build",
+                name,
+                opt_level
+            );
+            let cc = Command::new("cc")
+                .arg("-o")
+                .arg(&binary)
+                .arg(&c_file)
+                .arg("-lm")
+                .status()
+                .expect("can't run cc");
+            assert!(cc.success(), "{} {} doesn't compile", name, opt_level);
+
+            let interpreted = fusion(&[opt_level, script]);
+            let compiled = Command::new(&binary)
+                .output()
+                .expect("can't run the binary");
+            assert_eq!(
+                String::from_utf8_lossy(&compiled.stdout),
+                String::from_utf8_lossy(&interpreted.stdout),
+                "{} {} prints something else",
+                name,
+                opt_level
+            );
+            // Compile warnings come from `fusion build` instead.
+            let stderr = [build.stderr, compiled.stderr].concat();
+            assert_eq!(
+                String::from_utf8_lossy(&stderr),
+                String::from_utf8_lossy(&interpreted.stderr),
+                "{} {} fails differently",
+                name,
+                opt_level
+            );
+            assert_eq!(
+                compiled.status.code(),
+                interpreted.status.code(),
+                "{} {} exits differently",
+                name,
+                opt_level
+            );
+        }
+    }
+}